@@ -0,0 +1,111 @@
+// https://www.nesdev.org/wiki/APU
+// https://www.nesdev.org/wiki/APU_Frame_Counter
+
+use crate::region::Region;
+
+pub const APU_STATUS: u16       = 0x4015;
+pub const APU_FRAME_COUNTER: u16 = 0x4017;
+
+const FLAG_FRAME_INTERRUPT: u8  = 0b01000000;
+const FLAG_FIVE_STEP_MODE: u8   = 0b10000000;
+const FLAG_IRQ_INHIBIT: u8      = 0b01000000;
+
+/// Timing of the frame counter sequences in CPU cycles
+struct FrameCounterTiming {
+    /// CPU cycle of the 4-step sequence at which the frame interrupt is raised
+    four_step_irq_cycle: u32,
+    /// Length of the 4-step and 5-step sequences
+    four_step_period: u32,
+    five_step_period: u32,
+}
+
+const NTSC_FRAME_COUNTER: FrameCounterTiming = FrameCounterTiming {
+    four_step_irq_cycle: 29829,
+    four_step_period: 29830,
+    five_step_period: 37282,
+};
+
+const PAL_FRAME_COUNTER: FrameCounterTiming = FrameCounterTiming {
+    four_step_irq_cycle: 33251,
+    four_step_period: 33252,
+    five_step_period: 41566,
+};
+
+/// Audio processing unit, only the registers and the frame counter are emulated for now (no sound)
+pub struct Apu {
+    /// Last values written to the write-only registers $4000-$4017
+    registers: [u8; 0x18],
+    timing: &'static FrameCounterTiming,
+    /// CPU cycles elapsed in the current frame counter sequence
+    frame_cycles: u32,
+    five_step_mode: bool,
+    irq_inhibit: bool,
+    frame_interrupt: bool,
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            registers: [0; 0x18],
+            timing: &NTSC_FRAME_COUNTER,
+            frame_cycles: 0,
+            five_step_mode: false,
+            irq_inhibit: false,
+            frame_interrupt: false,
+        }
+    }
+
+    /// Sets the region, it selects the rates of the frame counter
+    pub fn set_region(&mut self, region: Region) {
+        self.timing = if region.has_pal_apu() { &PAL_FRAME_COUNTER } else { &NTSC_FRAME_COUNTER };
+    }
+
+    /// Reads the status register ($4015), it acknowledges the frame interrupt
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_interrupt = false;
+        status
+    }
+
+    /// Gets the status register ($4015) without acknowledging the frame interrupt
+    pub fn peek_status(&self) -> u8 {
+        if self.frame_interrupt { FLAG_FRAME_INTERRUPT } else { 0 }
+    }
+
+    /// Writes an APU register ($4000-$4013, $4015 and $4017)
+    pub fn write_register(&mut self, address: u16, val: u8) {
+        self.registers[(address - 0x4000) as usize] = val;
+
+        if address == APU_FRAME_COUNTER {
+            self.five_step_mode = val & FLAG_FIVE_STEP_MODE != 0;
+            self.irq_inhibit = val & FLAG_IRQ_INHIBIT != 0;
+            if self.irq_inhibit {
+                self.frame_interrupt = false;
+            }
+            self.frame_cycles = 0;
+        }
+    }
+
+    /// Advances the frame counter by the given number of CPU cycles
+    pub fn tick(&mut self, cycles: u32) {
+        let period = if self.five_step_mode { self.timing.five_step_period } else { self.timing.four_step_period };
+        let irq_cycle = self.timing.four_step_irq_cycle;
+
+        let previous_cycles = self.frame_cycles;
+        self.frame_cycles += cycles;
+
+        if !self.five_step_mode && !self.irq_inhibit
+            && previous_cycles < irq_cycle && self.frame_cycles >= irq_cycle {
+            self.frame_interrupt = true;
+        }
+
+        if self.frame_cycles >= period {
+            self.frame_cycles -= period;
+        }
+    }
+
+    /// Gets the level of the frame counter IRQ output
+    pub fn irq_line(&self) -> bool {
+        self.frame_interrupt
+    }
+}
@@ -0,0 +1,420 @@
+// https://www.nesdev.org/wiki/CPU_memory_map
+
+/// Address bus seen by the CPU
+pub trait Bus {
+    /// Reads the data at the given address, with the side effects of a CPU read. Each access takes
+    /// one CPU cycle, the devices attached to the bus run during it.
+    fn read(&mut self, address: u16) -> u8;
+
+    /// Writes the data at the given address, in one CPU cycle like the reads
+    fn write(&mut self, address: u16, val: u8);
+
+    /// Reads the data at the given address without any side effect, for debugging purposes
+    fn peek(&self, address: u16) -> u8;
+
+    /// Advances the devices attached to the bus by the given number of CPU cycles without any bus
+    /// access (halted CPU)
+    fn tick(&mut self, cycles: u32);
+
+    /// Takes the page of the pending OAM DMA transfer, if a write to $4014 requested one
+    fn take_oam_dma_request(&mut self) -> Option<u8> {
+        None
+    }
+
+    /// Takes the rising edge of the NMI line detected since the last call, the NMI is edge-triggered
+    fn take_nmi_edge(&mut self) -> bool {
+        false
+    }
+
+    // region Memory addressing
+    // All the addressing functions take the address of the opcode and return the effective address
+    // of its operand. The operand and the pointers are fetched with the bus accesses of the CPU, one
+    // per cycle with the dummy reads, see PeekBus to compute them without side effects.
+    // https://www.nesdev.org/wiki/CPU_addressing_modes
+    // https://www.nesdev.org/6502_cpu.txt
+
+    fn get_immediate(&mut self, address: u16) -> u16 {
+        address.wrapping_add(1)
+    }
+
+    fn get_zeropage(&mut self, address: u16) -> u16 {
+        self.read(address.wrapping_add(1)) as u16
+    }
+
+    /// Zero page indexed addressing wraps within the zero page, the base address is read while the
+    /// index is added
+    fn get_zeropage_x(&mut self, address: u16, x: u8) -> u16 {
+        let base = self.read(address.wrapping_add(1));
+        self.read(base as u16);
+        base.wrapping_add(x) as u16
+    }
+
+    /// Zero page indexed addressing wraps within the zero page, the base address is read while the
+    /// index is added
+    fn get_zeropage_y(&mut self, address: u16, y: u8) -> u16 {
+        let base = self.read(address.wrapping_add(1));
+        self.read(base as u16);
+        base.wrapping_add(y) as u16
+    }
+
+    fn get_absolute(&mut self, address: u16) -> u16 {
+        self.read16(address.wrapping_add(1))
+    }
+
+    /// Absolute indexed addressing, returns the effective address and whether a page was crossed,
+    /// see index_address for the dummy read
+    fn get_absolute_x(&mut self, address: u16, x: u8, page_penalty: bool) -> (u16, bool) {
+        let base = self.get_absolute(address);
+        self.index_address(base, x, page_penalty)
+    }
+
+    /// Absolute indexed addressing, returns the effective address and whether a page was crossed,
+    /// see index_address for the dummy read
+    fn get_absolute_y(&mut self, address: u16, y: u8, page_penalty: bool) -> (u16, bool) {
+        let base = self.get_absolute(address);
+        self.index_address(base, y, page_penalty)
+    }
+
+    /// Indirect addressing (JMP only), reproducing the 6502 bug where the high byte of a pointer
+    /// located at $xxFF is fetched from $xx00 instead of the next page
+    fn get_indirect(&mut self, address: u16) -> u16 {
+        let pointer = self.get_absolute(address);
+        let hi_pointer = (pointer & 0xff00) | (pointer.wrapping_add(1) & 0x00ff);
+        self.read(pointer) as u16 | ((self.read(hi_pointer) as u16) << 8)
+    }
+
+    fn get_relative(&mut self, address: u16) -> u16 {
+        // The offset is relative to the next instruction
+        let offset = self.read(address.wrapping_add(1)) as i8 as i16;
+        address.wrapping_add(2).wrapping_add(offset as u16)
+    }
+
+    /// Indexed indirect addressing, (zp,X)
+    fn get_indirect_x(&mut self, address: u16, x: u8) -> u16 {
+        let pointer = self.get_zeropage_x(address, x) as u8;
+        self.read_zeropage16(pointer)
+    }
+
+    /// Indirect indexed addressing, (zp),Y, returns the effective address and whether a page was
+    /// crossed, see index_address for the dummy read
+    fn get_indirect_y(&mut self, address: u16, y: u8, page_penalty: bool) -> (u16, bool) {
+        let pointer = self.read(address.wrapping_add(1));
+        let base = self.read_zeropage16(pointer);
+        self.index_address(base, y, page_penalty)
+    }
+
+    /// Adds the index to a base address. Before fixing the high byte, the CPU reads the address with
+    /// the high byte of the base: only on a page crossing for the instructions with a page penalty
+    /// (the reads), always for the others (the stores and the read-modify-write instructions).
+    fn index_address(&mut self, base: u16, index: u8, page_penalty: bool) -> (u16, bool) {
+        let address = base.wrapping_add(index as u16);
+        let page_crossed = (base & 0xff00) != (address & 0xff00);
+
+        if page_crossed || !page_penalty {
+            self.read((base & 0xff00) | (address & 0x00ff));
+        }
+
+        (address, page_crossed)
+    }
+
+    /// Reads a little-endian 16-bit value
+    fn read16(&mut self, address: u16) -> u16 {
+        self.read(address) as u16 | ((self.read(address.wrapping_add(1)) as u16) << 8)
+    }
+
+    /// Reads a little-endian 16-bit pointer from the zero page, the high byte of a pointer at $FF
+    /// wraps to $00
+    fn read_zeropage16(&mut self, pointer: u8) -> u16 {
+        self.read(pointer as u16) as u16 | ((self.read(pointer.wrapping_add(1) as u16) as u16) << 8)
+    }
+    // endregion
+}
+
+/// Bus reading through peek, the addressing functions then compute the effective addresses without
+/// any side effect, for debugging purposes
+pub struct PeekBus<'a, B: Bus + ?Sized>(pub &'a B);
+
+impl<B: Bus + ?Sized> Bus for PeekBus<'_, B> {
+    fn read(&mut self, address: u16) -> u8 {
+        self.0.peek(address)
+    }
+
+    fn write(&mut self, _address: u16, _val: u8) {}
+
+    fn peek(&self, address: u16) -> u8 {
+        self.0.peek(address)
+    }
+
+    fn tick(&mut self, _cycles: u32) {}
+}
+
+/// Flat 64kB RAM bus, without any device nor mirroring. Every read and write is recorded, to check
+/// the bus activity of the CPU cycle by cycle.
+#[cfg(test)]
+pub struct RamBus {
+    data: Vec<u8>,
+    accesses: Vec<(u16, u8, &'static str)>,
+    /// Number of accesses after which an NMI edge is raised
+    nmi_edge_at: Option<usize>,
+}
+
+#[cfg(test)]
+impl RamBus {
+    pub fn new() -> RamBus {
+        RamBus {
+            data: vec![0; 0xFFFF + 1],
+            accesses: Vec::new(),
+            nmi_edge_at: None,
+        }
+    }
+
+    /// Gets the accesses since the last clear: address, value and "read" or "write"
+    pub fn get_accesses(&self) -> &[(u16, u8, &'static str)] {
+        &self.accesses
+    }
+
+    pub fn clear_accesses(&mut self) {
+        self.accesses.clear();
+    }
+
+    /// Raises an NMI edge once the given number of accesses since the last clear is reached
+    pub fn set_nmi_edge_at(&mut self, accesses: usize) {
+        self.nmi_edge_at = Some(accesses);
+    }
+}
+
+#[cfg(test)]
+impl Bus for RamBus {
+    fn read(&mut self, address: u16) -> u8 {
+        let val = self.data[address as usize];
+        self.accesses.push((address, val, "read"));
+        val
+    }
+
+    fn write(&mut self, address: u16, val: u8) {
+        self.data[address as usize] = val;
+        self.accesses.push((address, val, "write"));
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.data[address as usize]
+    }
+
+    fn tick(&mut self, _cycles: u32) {}
+
+    fn take_nmi_edge(&mut self) -> bool {
+        match self.nmi_edge_at {
+            Some(accesses) if self.accesses.len() >= accesses => {
+                self.nmi_edge_at = None;
+                true
+            },
+            _ => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a memory with the given bytes written at the given address
+    fn memory_with(address: u16, bytes: &[u8]) -> RamBus {
+        let mut memory = RamBus::new();
+        for (i, b) in bytes.iter().enumerate() {
+            memory.write(address.wrapping_add(i as u16), *b);
+        }
+        memory
+    }
+
+    #[test]
+    fn immediate() {
+        let mut memory = RamBus::new();
+        assert_eq!(memory.get_immediate(0x8000), 0x8001);
+        assert_eq!(memory.get_immediate(0xffff), 0x0000);
+    }
+
+    #[test]
+    fn zeropage() {
+        let mut memory = memory_with(0x8000, &[0xa5, 0x42]);
+        assert_eq!(memory.get_zeropage(0x8000), 0x0042);
+    }
+
+    #[test]
+    fn zeropage_indexed() {
+        let mut memory = memory_with(0x8000, &[0xb5, 0x80]);
+        assert_eq!(memory.get_zeropage_x(0x8000, 0x0f), 0x008f);
+        assert_eq!(memory.get_zeropage_y(0x8000, 0x0f), 0x008f);
+    }
+
+    #[test]
+    fn zeropage_indexed_wraps_in_zeropage() {
+        let mut memory = memory_with(0x8000, &[0xb5, 0xff]);
+        assert_eq!(memory.get_zeropage_x(0x8000, 0x01), 0x0000);
+        assert_eq!(memory.get_zeropage_y(0x8000, 0x81), 0x0080);
+    }
+
+    #[test]
+    fn absolute() {
+        let mut memory = memory_with(0x8000, &[0xad, 0x34, 0x12]);
+        assert_eq!(memory.get_absolute(0x8000), 0x1234);
+    }
+
+    #[test]
+    fn absolute_indexed() {
+        let mut memory = memory_with(0x8000, &[0xbd, 0x34, 0x12]);
+        assert_eq!(memory.get_absolute_x(0x8000, 0x10, true).0, 0x1244);
+        assert_eq!(memory.get_absolute_y(0x8000, 0x10, true).0, 0x1244);
+    }
+
+    #[test]
+    fn absolute_indexed_page_crossing() {
+        let mut memory = memory_with(0x8000, &[0xbd, 0xff, 0x12]);
+        assert_eq!(memory.get_absolute_x(0x8000, 0x01, true).0, 0x1300);
+        assert_eq!(memory.get_absolute_y(0x8000, 0xff, true).0, 0x13fe);
+    }
+
+    #[test]
+    fn absolute_indexed_wraps_around_address_space() {
+        let mut memory = memory_with(0x8000, &[0xbd, 0xff, 0xff]);
+        assert_eq!(memory.get_absolute_x(0x8000, 0x02, true).0, 0x0001);
+        assert_eq!(memory.get_absolute_y(0x8000, 0x02, true).0, 0x0001);
+    }
+
+    #[test]
+    fn indirect() {
+        let mut memory = memory_with(0x8000, &[0x6c, 0x20, 0x01]);
+        memory.write(0x0120, 0xfc);
+        memory.write(0x0121, 0xba);
+        assert_eq!(memory.get_indirect(0x8000), 0xbafc);
+    }
+
+    #[test]
+    fn indirect_page_boundary_bug() {
+        let mut memory = memory_with(0x8000, &[0x6c, 0xff, 0x02]);
+        memory.write(0x02ff, 0x34);
+        memory.write(0x0300, 0x56);
+        memory.write(0x0200, 0x12);
+        assert_eq!(memory.get_indirect(0x8000), 0x1234);
+    }
+
+    #[test]
+    fn relative_forward() {
+        let mut memory = memory_with(0x8000, &[0xd0, 0x10]);
+        assert_eq!(memory.get_relative(0x8000), 0x8012);
+    }
+
+    #[test]
+    fn relative_backward() {
+        let mut memory = memory_with(0x8000, &[0xd0, 0xfc]);
+        assert_eq!(memory.get_relative(0x8000), 0x7ffe);
+    }
+
+    #[test]
+    fn relative_page_crossing() {
+        let mut memory = memory_with(0x80f0, &[0xd0, 0x7f]);
+        assert_eq!(memory.get_relative(0x80f0), 0x8171);
+    }
+
+    #[test]
+    fn indexed_indirect() {
+        let mut memory = memory_with(0x8000, &[0xa1, 0x20]);
+        memory.write(0x0024, 0x74);
+        memory.write(0x0025, 0x20);
+        assert_eq!(memory.get_indirect_x(0x8000, 0x04), 0x2074);
+    }
+
+    #[test]
+    fn indexed_indirect_wraps_in_zeropage() {
+        let mut memory = memory_with(0x8000, &[0xa1, 0xfe]);
+        memory.write(0x00ff, 0x74);
+        memory.write(0x0000, 0x20);
+        assert_eq!(memory.get_indirect_x(0x8000, 0x01), 0x2074);
+        memory.write(0x0003, 0x33);
+        memory.write(0x0004, 0x44);
+        assert_eq!(memory.get_indirect_x(0x8000, 0x05), 0x4433);
+    }
+
+    #[test]
+    fn indirect_indexed() {
+        let mut memory = memory_with(0x8000, &[0xb1, 0x86]);
+        memory.write(0x0086, 0x28);
+        memory.write(0x0087, 0x40);
+        assert_eq!(memory.get_indirect_y(0x8000, 0x10, true).0, 0x4038);
+    }
+
+    #[test]
+    fn indirect_indexed_page_crossing() {
+        let mut memory = memory_with(0x8000, &[0xb1, 0x86]);
+        memory.write(0x0086, 0xf8);
+        memory.write(0x0087, 0x40);
+        assert_eq!(memory.get_indirect_y(0x8000, 0x10, true).0, 0x4108);
+    }
+
+    #[test]
+    fn indirect_indexed_pointer_wraps_in_zeropage() {
+        let mut memory = memory_with(0x8000, &[0xb1, 0xff]);
+        memory.write(0x00ff, 0x00);
+        memory.write(0x0000, 0x30);
+        memory.write(0x0100, 0x99);
+        assert_eq!(memory.get_indirect_y(0x8000, 0x01, true).0, 0x3001);
+    }
+
+    #[test]
+    fn zeropage_indexed_reads_base_address() {
+        let mut memory = memory_with(0x8000, &[0xb5, 0x80]);
+        memory.clear_accesses();
+        memory.get_zeropage_x(0x8000, 0x0f);
+        assert_eq!(memory.get_accesses(), &[(0x8001, 0x80, "read"), (0x0080, 0x00, "read")]);
+    }
+
+    #[test]
+    fn absolute_indexed_reads_unfixed_address() {
+        let mut memory = memory_with(0x8000, &[0xbd, 0xff, 0x12]);
+
+        // Without page crossing, a read has no dummy read and a store reads the effective address
+        memory.clear_accesses();
+        assert_eq!(memory.get_absolute_x(0x8000, 0x00, true), (0x12ff, false));
+        assert_eq!(memory.get_accesses().len(), 2);
+        memory.clear_accesses();
+        assert_eq!(memory.get_absolute_x(0x8000, 0x00, false), (0x12ff, false));
+        assert_eq!(memory.get_accesses()[2], (0x12ff, 0x00, "read"));
+
+        // On a page crossing, the high byte is not fixed yet
+        memory.clear_accesses();
+        assert_eq!(memory.get_absolute_y(0x8000, 0x01, true), (0x1300, true));
+        assert_eq!(memory.get_accesses()[2], (0x1200, 0x00, "read"));
+    }
+
+    #[test]
+    fn indexed_indirect_reads_pointer_before_indexing() {
+        let mut memory = memory_with(0x8000, &[0xa1, 0x20]);
+        memory.write(0x0024, 0x74);
+        memory.write(0x0025, 0x20);
+        memory.clear_accesses();
+        memory.get_indirect_x(0x8000, 0x04);
+        assert_eq!(memory.get_accesses(), &[
+            (0x8001, 0x20, "read"),
+            (0x0020, 0x00, "read"),
+            (0x0024, 0x74, "read"),
+            (0x0025, 0x20, "read"),
+        ]);
+    }
+
+    #[test]
+    fn indirect_indexed_reads_unfixed_address_on_page_crossing() {
+        let mut memory = memory_with(0x8000, &[0xb1, 0x86]);
+        memory.write(0x0086, 0xf8);
+        memory.write(0x0087, 0x40);
+        memory.clear_accesses();
+        assert_eq!(memory.get_indirect_y(0x8000, 0x10, true), (0x4108, true));
+        assert_eq!(memory.get_accesses().last(), Some(&(0x4008, 0x00, "read")));
+    }
+
+    #[test]
+    fn peek_bus_has_no_side_effect() {
+        let mut memory = memory_with(0x8000, &[0xbd, 0xff, 0x12]);
+        memory.clear_accesses();
+        assert_eq!(PeekBus(&memory).get_absolute_x(0x8000, 0x01, false), (0x1300, true));
+        assert!(memory.get_accesses().is_empty());
+    }
+}
@@ -1,11 +1,72 @@
-const JOY_RIGHT: u8     = 0b10000000;
-const JOY_LEFT: u8      = 0b01000000;
-const JOY_DOWN: u8      = 0b00100000;
-const JOY_UP: u8        = 0b00010000;
-const JOY_START: u8     = 0b00001000;
-const JOY_SELECT: u8    = 0b00000100;
-const JOY_BUTTON_B: u8  = 0b00000010;
-const JOY_BUTTON_A: u8  = 0b00000001;
-
-const CONTROLLER_1_ADDRESS: u16 = 0x4016;
-const CONTROLLER_2_ADDRESS: u16 = 0x4017;
+
+// https://www.nesdev.org/wiki/Standard_controller
+
+// The buttons are reported serially in the order of the bits, from A to RIGHT
+pub const JOY_RIGHT: u8     = 0b10000000;
+pub const JOY_LEFT: u8      = 0b01000000;
+pub const JOY_DOWN: u8      = 0b00100000;
+pub const JOY_UP: u8        = 0b00010000;
+pub const JOY_START: u8     = 0b00001000;
+pub const JOY_SELECT: u8    = 0b00000100;
+pub const JOY_BUTTON_B: u8  = 0b00000010;
+pub const JOY_BUTTON_A: u8  = 0b00000001;
+
+pub const CONTROLLER_1_ADDRESS: u16 = 0x4016;
+pub const CONTROLLER_2_ADDRESS: u16 = 0x4017;
+
+/// Standard controller, read through a 8-bit shift register
+pub struct Controller {
+    /// Currently pressed buttons
+    buttons: u8,
+    /// Buttons latched by the strobe, shifted out on each read
+    shift_register: u8,
+    /// While the strobe is high, the shift register is continuously reloaded
+    strobe: bool,
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        Controller {
+            buttons: 0,
+            shift_register: 0,
+            strobe: false,
+        }
+    }
+
+    /// Sets the state of the given button (JOY_* constants)
+    pub fn set_button(&mut self, button: u8, pressed: bool) {
+        if pressed {
+            self.buttons |= button;
+        } else {
+            self.buttons &= !button;
+        }
+    }
+
+    /// Writes the strobe bit ($4016)
+    pub fn write(&mut self, val: u8) {
+        self.strobe = val & 0x01 != 0;
+        if self.strobe {
+            self.shift_register = self.buttons;
+        }
+    }
+
+    /// Reads the next button state in bit 0, once all the buttons have been read it returns 1
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons & 0x01;
+        }
+
+        let val = self.shift_register & 0x01;
+        self.shift_register = (self.shift_register >> 1) | 0x80;
+        val
+    }
+
+    /// Gets the value of the next read, without shifting the register
+    pub fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons & 0x01
+        } else {
+            self.shift_register & 0x01
+        }
+    }
+}
@@ -1,12 +1,19 @@
 use crate::rom_file::RomFile;
+use crate::bus::Bus;
 use crate::memory::*;
+use crate::opcodes::{Opcode, OPCODES};
 
 use log::{debug, info, error, warn};
 
 // https://github.com/ltriant/nes/blob/master/src/cpu.rs
 // https://emudev.de/nes-emulator/opcodes-and-addressing-modes-the-6502/
 
-const STACK_INIT: u8                = 0xfd;
+const NMI_VECTOR: u16               = 0xfffa;
+const RESET_VECTOR: u16             = 0xfffc;
+const IRQ_VECTOR: u16               = 0xfffe;
+
+/// Number of cycles taken by the interrupt sequences (RESET, NMI, IRQ and BRK)
+const INTERRUPT_CYCLES: u64         = 7;
 
 const FLAG_CARRY: u8                = 0b00000001;
 const FLAG_ZERO: u8                 = 0b00000010;
@@ -17,10 +24,15 @@ const FLAG_U: u8                    = 0b00100000;
 const FLAG_OVERFLOW: u8             = 0b01000000;
 const FLAG_NEGATIVE: u8             = 0b10000000;
 
-pub const FLAG_NMI_OCCURRED: u8     = 0b10000000;
-pub const FLAG_VBLANK: u8           = 0b10000000;
+// IRQ sources, the IRQ line is asserted as long as at least one of them is
+pub const IRQ_APU_FRAME: u8         = 0b00000001;
+pub const IRQ_DMC: u8               = 0b00000010;
+pub const IRQ_MAPPER: u8            = 0b00000100;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Instruction {
+    ADC,
+    AHX,
     ALR,
     ANC,
     AND,
@@ -28,25 +40,36 @@ pub enum Instruction {
     ASL,
     AXS,
     BCC,
+    BCS,
     BEQ,
     BIT,
     BMI,
+    BNE,
     BPL,
     BRK,
     BVC,
     BVS,
     CLC,
+    CLD,
     CLI,
+    CLV,
     CMP,
+    CPX,
+    CPY,
     DCP,
     DEC,
     DEX,
+    DEY,
     EOR,
     INC,
+    INX,
+    INY,
     ISC,
     JMP,
     JSR,
+    LAS,
     LAX,
+    LDA,
     LDX,
     LDY,
     LSR,
@@ -62,17 +85,27 @@ pub enum Instruction {
     RRA,
     RTI,
     RTS,
+    SAX,
+    SBC,
     SEC,
     SED,
     SEI,
     SHX,
     SHY,
+    SLO,
     SRE,
     STA,
     STP,
-    SLO,
+    STX,
+    STY,
     TAS,
+    TAX,
+    TAY,
     TSX,
+    TXA,
+    TXS,
+    TYA,
+    XAA,
 }
 
 pub struct Cpu {
@@ -92,11 +125,24 @@ pub struct Cpu {
     // endregion
 
     /// Cycles count
-    cycles: u32
+    cycles: u64,
+
+    /// True once a STP/KIL opcode has jammed the CPU, only a reset recovers from it
+    halted: bool,
+
+    // region Interrupt lines
+    /// Set on a rising edge of the NMI line, until the NMI is serviced
+    nmi_pending: bool,
+    /// IRQ sources currently asserting the IRQ line
+    irq_lines: u8,
+    /// Value of the interrupt disable flag seen by the IRQ polling, it lags one instruction behind
+    /// after CLI, SEI and PLP
+    irq_inhibit: bool,
+    // endregion
 }
 
 impl Cpu {
-    pub fn new(mem: &Memory) -> Cpu {
+    pub fn new<B: Bus>(bus: &mut B) -> Cpu {
         let mut cpu = Cpu {
             a: 0,
             x: 0,
@@ -104,381 +150,315 @@ impl Cpu {
             pc: 0,
             s: 0,
             p: 0,
-            cycles: 0
+            cycles: 0,
+            halted: false,
+            nmi_pending: false,
+            irq_lines: 0,
+            irq_inhibit: true,
         };
-        cpu.reset(mem);
+        cpu.reset(bus);
 
         return cpu;
     }
 
-    pub fn reset(&mut self, memory: &Memory) {
-        let lo = memory.read(0xFFFC) as u16;
-        let hi = memory.read(0xFFFD) as u16;
-        let addr = (hi << 8) | lo;
-        self.pc = addr;
+    /// Performs the RESET sequence: like an interrupt but the stack writes are inhibited, so only
+    /// the stack pointer is decremented, and A, X and Y keep their values
+    pub fn reset<B: Bus>(&mut self, bus: &mut B) {
+        bus.read(self.pc);
+        bus.read(self.pc);
+        for _ in 0..3 {
+            // The stack writes are turned into reads
+            bus.read(0x0100 | (self.s as u16));
+            self.s = self.s.wrapping_sub(1);
+        }
+        self.pc = bus.read16(RESET_VECTOR);
+
+        self.p |= FLAG_INTERRUPT_DISABLE | FLAG_U;
+        // An NMI edge during the sequence is lost
+        bus.take_nmi_edge();
+
+        self.nmi_pending = false;
+        self.irq_inhibit = true;
+        self.cycles = INTERRUPT_CYCLES;
+        self.halted = false;
+    }
+
+    /// True if the CPU has been halted by a STP/KIL opcode
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Executes the next instruction and returns the number of cycles it took
+    pub fn step<B: Bus>(&mut self, bus: &mut B) -> u32 {
+        if self.halted {
+            // The clock keeps running while the CPU is jammed
+            self.cycles += 1;
+            bus.tick(1);
+            return 1;
+        }
+
+        let start_cycles = self.cycles;
+
+        // Check interrupt, the interrupt sequence replaces the next instruction
+        self.nmi_pending |= bus.take_nmi_edge();
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.hardware_interrupt(bus, NMI_VECTOR);
+        } else if self.irq_lines != 0 && !self.irq_inhibit {
+            self.hardware_interrupt(bus, IRQ_VECTOR);
+        } else {
+            let opcode = bus.read(self.pc);
+            debug!("A: 0x{0:02x}, X: 0x{1:02x}, Y: 0x{2:02x}, P: 0x{3:02x}, SP: 0x{4:02x}", self.a, self.x, self.y, self.p, self.s);
+            debug!("PC: 0x{0:02x}, OpCode: 0x{1:02x}", self.pc, opcode);
+            self.execute_opcode(opcode, bus);
+        }
+
+        // The CPU is halted after the instruction that requested the OAM DMA
+        if let Some(page) = bus.take_oam_dma_request() {
+            self.oam_dma(bus, page);
+        }
+
+        (self.cycles - start_cycles) as u32
+    }
+
+    /// Copies the page $XX00-$XXFF to the PPU OAM through OAMDATA, the CPU is stalled for 513 cycles
+    /// (one halt cycle, then 256 read/write pairs) plus one alignment cycle when the DMA starts on an
+    /// odd cycle
+    // https://www.nesdev.org/wiki/DMA#OAM_DMA
+    fn oam_dma<B: Bus>(&mut self, bus: &mut B, page: u8) {
+        let wait_cycles = if self.cycles % 2 == 1 { 2 } else { 1 };
+        self.cycles += wait_cycles;
+        bus.tick(wait_cycles as u32);
+
+        for i in 0..=0xff {
+            let val = bus.read((page as u16) << 8 | i);
+            bus.write(OAM_DATA, val);
+        }
+        self.cycles += 512;
+    }
+
+    /// Gets the total number of cycles elapsed since the last reset
+    pub fn get_cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // region Registers access
+    pub fn get_a(&self) -> u8 {
+        self.a
+    }
+
+    #[cfg(test)]
+    pub fn set_a(&mut self, a: u8) {
+        self.a = a;
+    }
+
+    pub fn get_x(&self) -> u8 {
+        self.x
+    }
+
+    #[cfg(test)]
+    pub fn set_x(&mut self, x: u8) {
+        self.x = x;
+    }
+
+    pub fn get_y(&self) -> u8 {
+        self.y
+    }
+
+    #[cfg(test)]
+    pub fn set_y(&mut self, y: u8) {
+        self.y = y;
+    }
+
+    pub fn get_pc(&self) -> u16 {
+        self.pc
+    }
 
-        self.p = 0x4;
+    #[cfg(test)]
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
 
-        self.s = STACK_INIT;
-        self.a = 0;
-        self.x = 0;
-        self.y = 0;
+    pub fn get_s(&self) -> u8 {
+        self.s
+    }
 
-        // self.interrupt = None;
-        // self.stall = None;
-        self.cycles = 0;
+    #[cfg(test)]
+    pub fn set_s(&mut self, s: u8) {
+        self.s = s;
     }
 
-    pub fn step(&mut self, memory: &mut Memory) {
-        // Check interrupt
-        // TODO
+    pub fn get_p(&self) -> u8 {
+        self.p
+    }
 
-        let opcode = memory.read(self.pc);
-        debug!("A: 0x{0:02x}, X: 0x{1:02x}, Y: 0x{2:02x}, P: 0x{3:02x}, SP: 0x{4:02x}", self.a, self.x, self.y, self.p, self.s);
-        debug!("PC: 0x{0:02x}, OpCode: 0x{1:02x}", self.pc, opcode);
-        self.execute_opcode(opcode, memory);
+    #[cfg(test)]
+    pub fn set_p(&mut self, p: u8) {
+        self.p = p;
     }
+    // endregion
 
-    pub fn execute_opcode(&mut self, opcode: u8, memory: &mut Memory) {
-        match opcode {
-            0x00 => {   // BRK
-                self.brk(memory);
-                self.cycles += 7;
+    pub fn execute_opcode<B: Bus>(&mut self, opcode: u8, bus: &mut B) {
+        let op = &OPCODES[opcode as usize];
+        let interrupt_disabled = self.get_status(FLAG_INTERRUPT_DISABLE);
+        // JSR fetches the high byte of its target after pushing the return address
+        let (address, page_crossed) = match op.instruction {
+            Instruction::JSR => (0, false),
+            _ => self.fetch_operand_address(op, bus),
+        };
+
+        self.pc = self.pc.wrapping_add(op.bytes as u16);
+        self.cycles += op.cycles as u64;
+
+        // Indexed reads take an extra cycle to fix the high byte of the address when crossing a page,
+        // stores and read-modify-write instructions always pay for it in their base cycles
+        if op.page_penalty && page_crossed {
+            self.cycles += 1;
+        }
+
+        match op.instruction {
+            Instruction::ADC => self.adc(bus, address),
+            Instruction::AND => self.and(bus, address),
+            Instruction::ASL => match op.mode {
+                AddressingMode::Accumulator => self.asl_akk(),
+                _ => { self.asl(bus, address); }
+            },
+            Instruction::BCC => self.bcc(bus, address),
+            Instruction::BCS => self.bcs(bus, address),
+            Instruction::BEQ => self.beq(bus, address),
+            Instruction::BIT => self.bit(bus, address),
+            Instruction::BMI => self.bmi(bus, address),
+            Instruction::BNE => self.bne(bus, address),
+            Instruction::BPL => self.bpl(bus, address),
+            Instruction::BRK => self.brk(bus),
+            Instruction::BVC => self.bvc(bus, address),
+            Instruction::BVS => self.bvs(bus, address),
+            Instruction::CLC => self.clc(),
+            Instruction::CLD => self.cld(),
+            Instruction::CLI => self.cli(),
+            Instruction::CLV => self.clv(),
+            Instruction::CMP => self.cmp(bus, address),
+            Instruction::CPX => self.cpx(bus, address),
+            Instruction::CPY => self.cpy(bus, address),
+            Instruction::DEC => { self.dec(bus, address); }
+            Instruction::DEX => self.dex(),
+            Instruction::DEY => self.dey(),
+            Instruction::EOR => self.eor(bus, address),
+            Instruction::INC => { self.inc(bus, address); }
+            Instruction::INX => self.inx(),
+            Instruction::INY => self.iny(),
+            Instruction::JMP => self.jmp(address),
+            Instruction::JSR => self.jsr(bus),
+            Instruction::LDA => self.lda(bus, address),
+            Instruction::LDX => self.ldx(bus, address),
+            Instruction::LDY => self.ldy(bus, address),
+            Instruction::LSR => match op.mode {
+                AddressingMode::Accumulator => self.lsr_akk(),
+                _ => { self.lsr(bus, address); }
+            },
+            Instruction::NOP => match op.mode {
+                AddressingMode::Implied => self.nop(),
+                _ => self.nop_read(bus, address),
+            },
+            Instruction::ORA => self.ora(bus, address),
+            Instruction::PHA => self.pha(bus),
+            Instruction::PHP => self.php(bus),
+            Instruction::PLA => self.pla(bus),
+            Instruction::PLP => self.plp(bus),
+            Instruction::ROL => match op.mode {
+                AddressingMode::Accumulator => self.rol_akk(),
+                _ => { self.rol(bus, address); }
+            },
+            Instruction::ROR => match op.mode {
+                AddressingMode::Accumulator => self.ror_akk(),
+                _ => { self.ror(bus, address); }
+            },
+            Instruction::RTI => self.rti(bus),
+            Instruction::RTS => self.rts(bus),
+            Instruction::SBC => self.sbc(bus, address),
+            Instruction::SEC => self.sec(),
+            Instruction::SED => self.sed(),
+            Instruction::SEI => self.sei(),
+            Instruction::STA => self.sta(bus, address),
+            Instruction::STX => self.stx(bus, address),
+            Instruction::STY => self.sty(bus, address),
+            Instruction::TAX => self.tax(),
+            Instruction::TAY => self.tay(),
+            Instruction::TSX => self.tsx(),
+            Instruction::TXA => self.txa(),
+            Instruction::TXS => self.txs(),
+            Instruction::TYA => self.tya(),
+
+            // Unofficial opcodes
+            Instruction::AHX => self.ahx(bus, address),
+            Instruction::ALR => self.alr(bus, address),
+            Instruction::ANC => self.anc(bus, address),
+            Instruction::ARR => self.arr(bus, address),
+            Instruction::AXS => self.axs(bus, address),
+            Instruction::DCP => self.dcp(bus, address),
+            Instruction::ISC => self.isc(bus, address),
+            Instruction::LAS => self.las(bus, address),
+            Instruction::LAX => match op.mode {
+                AddressingMode::Immediate => self.lxa(bus, address),
+                _ => self.lax(bus, address),
+            },
+            Instruction::RLA => self.rla(bus, address),
+            Instruction::RRA => self.rra(bus, address),
+            Instruction::SAX => self.sax(bus, address),
+            Instruction::SHX => self.shx(bus, address),
+            Instruction::SHY => self.shy(bus, address),
+            Instruction::SLO => self.slo(bus, address),
+            Instruction::SRE => self.sre(bus, address),
+            Instruction::STP => self.stp(),
+            Instruction::TAS => self.tas(bus, address),
+            Instruction::XAA => self.xaa(bus, address),
+        }
+
+        // The IRQ line is polled before the last cycle of the instruction, so CLI, SEI and PLP only
+        // affect the interrupt after the next instruction
+        self.irq_inhibit = match op.instruction {
+            Instruction::CLI | Instruction::SEI | Instruction::PLP => interrupt_disabled,
+            _ => self.get_status(FLAG_INTERRUPT_DISABLE),
+        };
+    }
+
+    /// Fetches the operand of the instruction located at PC and computes its effective address, and
+    /// whether indexing it crossed a page. Each cycle of the addressing mode accesses the bus, the
+    /// dummy reads included.
+    // https://www.nesdev.org/6502_cpu.txt
+    fn fetch_operand_address<B: Bus>(&self, op: &Opcode, bus: &mut B) -> (u16, bool) {
+        match op.mode {
+            AddressingMode::Immediate => (bus.get_immediate(self.pc), false),
+            AddressingMode::ZeroPage => (bus.get_zeropage(self.pc), false),
+            AddressingMode::ZeroPageX => (bus.get_zeropage_x(self.pc, self.x), false),
+            AddressingMode::ZeroPageY => (bus.get_zeropage_y(self.pc, self.y), false),
+            AddressingMode::Absolute => (bus.get_absolute(self.pc), false),
+            AddressingMode::AbsoluteX => bus.get_absolute_x(self.pc, self.x, op.page_penalty),
+            AddressingMode::AbsoluteY => bus.get_absolute_y(self.pc, self.y, op.page_penalty),
+            AddressingMode::Indirect => (bus.get_indirect(self.pc), false),
+            AddressingMode::IndexedIndirect => (bus.get_indirect_x(self.pc, self.x), false),
+            AddressingMode::IndirectIndexed => bus.get_indirect_y(self.pc, self.y, op.page_penalty),
+            AddressingMode::Relative => (bus.get_relative(self.pc), false),
+            AddressingMode::Implied | AddressingMode::Accumulator => {
+                // The byte following the opcode is read and discarded
+                bus.read(self.pc.wrapping_add(1));
+                (0, false)
             },
-            0x01 => {   // ORA INX
-                self.ora(memory, memory.get_indirect_x(self.pc, self.x));
-                self.pc += 2;
-                self.cycles += 6;
-            }
-            0x05 => {   // ORA ZP
-                self.ora(memory, memory.get_zeropage(self.pc));
-                self.pc += 2;
-                self.cycles += 3;
-            }
-            0x06 => {   // ASL ZP
-                self.asl(memory, memory.get_zeropage(self.pc));
-                self.pc += 2;
-                self.cycles += 5;
-            }
-            0x08 => {   // PHP IMP
-                self.php(memory);
-                self.pc += 1;
-                self.cycles += 3;
-            }
-            0x09 => {   // ORA IMM
-                self.ora(memory, memory.get_immediate(self.pc));
-                self.pc += 2;
-                self.cycles += 4;
-            }
-            0x0a => {   // ASL AKK
-                self.asl_akk();
-                self.pc += 1;
-                self.cycles += 2;
-            }
-            0x0c => {   // NOP
-                self.nop();
-                self.pc += 1;
-            }
-            0x0d => {   // ORA ABS
-                self.ora(memory, memory.get_absolute(self.pc));
-                self.pc += 3;
-                self.cycles += 4;
-            }
-            0x0e => {   // ASL ABS
-                self.asl(memory, memory.get_absolute(self.pc));
-                self.pc += 3;
-                self.cycles += 6;
-            }
-            0x10 => {   // BPL REL
-                self.bpl(memory.get_relative(self.pc));
-                self.pc += 2;
-            }
-            0x11 => {   // ORA INY
-                self.ora(memory, memory.get_indirect_y(self.pc, self.y));
-                self.pc += 2;
-                // TODO Cycles
-            }
-            0x15 => {   // ORA ZPX
-                self.ora(memory, memory.get_zeropage_x(self.pc, self.x));
-                self.pc += 1;
-                self.cycles += 4;
-            }
-            0x16 => {   // ASL ZPX
-                self.asl(memory, memory.get_zeropage_x(self.pc, self.x));
-                self.pc += 1;
-                self.cycles += 6;
-            }
-            0x18 => {
-                self.clc();
-                self.pc += 1;
-                self.cycles += 2;
-            }
-            0x19 => {   // ORA ABY
-                self.ora(memory,memory.get_absolute_y(self.pc, self.y));
-                self.pc += 1;
-                // TODO Cycles
-            }
-            0x1a => {   // NOP
-                self.nop();
-                self.pc += 1;
-                // TODO Cycles
-            }
-            0x1c => {   // NOP
-                self.nop();
-                self.pc += 1;
-                // TODO Cycles
-            }
-            0x1d => {   // ORA ABX
-                self.ora(memory, memory.get_absolute_x(self.pc, self.x));
-                self.pc += 1;
-                // TODO Cycles
-            }
-            0x1e => {   // ASL ABX
-                self.asl(memory, memory.get_absolute_x(self.pc, self.x));
-                self.pc += 1;
-                self.cycles += 7;
-            }
-            0x20 => {   // JSR ABS
-                self.jsr(memory, memory.get_absolute(self.pc), 2);
-                self.cycles += 6;
-            }
-            0x21 => {   // AND INX
-                self.and(memory, memory.get_indirect_x(self.pc, self.x));
-                self.pc += 2;
-                self.cycles += 6;
-            }
-            0x24 => {   // BIT ZP
-                self.bit(memory, memory.get_zeropage(self.pc));
-                self.pc += 2;
-                self.cycles += 3;
-            }
-            0x25 => {   // AND ZP
-                self.and(memory, memory.get_zeropage(self.pc));
-                self.pc += 2;
-                self.cycles += 3;
-            }
-            0x26 => {   // ROL ZP
-                self.rol(memory, memory.get_zeropage(self.pc));
-                self.pc += 2;
-                self.cycles += 5;
-            }
-            0x28 => {   // PLP IMP
-                self.plp(memory);
-                self.pc += 1;
-                self.cycles += 4;
-            }
-            0x29 => {   // AND IMM
-                self.and(memory, memory.get_zeropage(self.pc));
-                self.pc += 2;
-                self.cycles += 2;
-            }
-            0x2a => {   // ROL AKK
-                self.rol_akk();
-                self.pc += 1;
-                self.cycles += 2;
-            }
-            0x2c => {   // BIT ABS
-                self.bit(memory, memory.get_absolute(self.pc));
-                self.pc += 3;
-                self.cycles += 4;
-            }
-            0x2d => {   // AND ABS
-                self.and(memory, memory.get_absolute(self.pc));
-                self.pc += 3;
-                self.cycles += 4;
-            }
-            0x2e => {   // ROL ABS
-                self.rol(memory, memory.get_absolute(self.pc));
-                self.pc += 3;
-                self.cycles += 6;
-            }
-            0x30 => {   // BMI REL
-                self.bmi(memory.get_relative(self.pc));
-                self.pc += 2;
-                // TODO Cycles
-            }
-            0x31 => {   // AND INY
-                self.and(memory, memory.get_indirect_y(self.pc, self.y));
-                self.pc += 2;
-                // TODO Cycles
-            }
-            0x35 => {   // AND ZPX
-                self.and(memory, memory.get_zeropage_x(self.pc, self.x));
-                self.pc += 1;
-                self.cycles += 4;
-            }
-            0x36 => {   // ROL ZPX
-                self.rol(memory, memory.get_zeropage_x(self.pc, self.x));
-                self.pc += 1;
-                self.cycles += 5;
-            }
-            0x38 => {   // SEC IMP
-                self.sec();
-                self.pc += 1;
-                self.cycles += 2;
-            }
-            0x39 => {   // AND ABY
-                self.and(memory, memory.get_absolute_y(self.pc, self.y));
-                self.pc += 1;
-                // TODO Cycles
-            }
-            0x3d => {   // AND ABX
-                self.and(memory, memory.get_absolute_x(self.pc, self.x));
-                self.pc += 1;
-                // TODO Cycles
-            }
-            0x3e => {   // ROL ABX
-                self.rol(memory, memory.get_absolute_x(self.pc, self.x));
-                self.pc += 1;
-                self.cycles += 7;
-            }
-            0x40 => {   // RTI IMP
-                self.rti(memory);
-                self.cycles += 6;
-            }
-            0x4a => {   // LSR AKK
-                self.lsr_akk();
-                self.pc += 1;
-            }
-            0x4c => {   // JMP ABS
-                self.jmp(memory.get_absolute(self.pc));
-            }
-            0x58 => {   // CLI IMP
-                self.cli();
-                self.pc += 1;
-            }
-            0x60 => {   // RTS IMP
-                self.rts(memory);
-                self.cycles += 6;
-            }
-            0x78 => {   // SEI IMP
-                self.sei();
-                self.pc += 1;
-            }
-            0x85 => {   // STA ZP
-                self.sta(memory, memory.get_zeropage(self.pc));
-                self.pc += 2;
-            }
-            0x86 => {   // STX ZP
-                self.stx(memory, memory.get_zeropage(self.pc));
-                self.pc += 2;
-            }
-            0x88 => {   // DEY IMP
-                self.dey();
-                self.pc += 1;
-            }
-            0x8a => {   // TXA IMP
-                self.txa();
-                self.pc += 1;
-            }
-            0x8c => {   // STY ABS
-                self.sty(memory, memory.get_absolute(self.pc));
-                self.pc += 3;
-                self.cycles += 4;
-            }
-            0x8d => {   // STA ABS
-                self.sta(memory, memory.get_absolute(self.pc));
-                self.pc += 3;
-                self.cycles += 4;
-            }
-            0x8e => {   // STX ABS
-                self.stx(memory, memory.get_absolute(self.pc));
-                self.pc += 3;
-                self.cycles += 4;
-            }
-            0x91 => {   // STA INY
-                self.sta(memory, memory.get_indirect_y(self.pc, self.y));
-                self.pc += 2;
-                self.cycles += 6;
-            }
-            0x99 => {   // STA ABY
-                self.sta(memory, memory.get_absolute_y(self.pc, self.y));
-                self.pc += 3;
-                self.cycles += 5;
-            }
-            0x9a => {   // TXS IMP
-                self.txs();
-                self.pc += 1;
-            }
-            0xa2 => {   // LDX IMM
-                self.ldx(memory, memory.get_immediate(self.pc));
-                self.pc += 2;
-            }
-            0xa9 => {   // LDA IMM
-                self.lda(memory, memory.get_immediate(self.pc));
-                self.pc += 2;
-            }
-            0xa0 => {   // LDY IMM
-                self.ldy(memory, memory.get_immediate(self.pc));
-                self.pc += 2;
-            }
-            0xad => {   // LDA ABS
-                self.lda(memory, memory.get_absolute(self.pc));
-                self.pc += 3;
-                self.cycles += 4
-            }
-            0xb0 => {   // BCS REL
-                self.bcs(memory.get_relative(self.pc));
-                self.pc += 2;
-            }
-            0xb8 => {   // CLV IMP
-                self.clv();
-                self.pc += 1;
-            }
-            0xbd => {   // LDA ABX
-                self.lda(memory, memory.get_absolute_x(self.pc, self.x));
-                self.pc += 3;
-            }
-            0xc0 => {   // CPY IMM
-                self.cpy(memory, memory.get_immediate(self.pc));
-                self.pc += 2;
-                self.cycles += 2;
-            }
-            0xc8 => {   // INY IMP
-                self.iny();
-                self.pc += 1;
-                self.cycles += 2;
-            }
-            0xc9 => {   // CMP IMM
-                self.cmp(memory, memory.get_immediate(self.pc));
-                self.pc += 2;
-                self.cycles += 2;
-            }
-            0xca => {   // DEX IMP
-                self.dex();
-                self.pc += 1;
-            }
-            0xd0 => {   // BNE REL
-                self.bne(memory.get_relative(self.pc));
-                self.pc += 2;
-            }
-            0xd8 => {   // CLD IMP
-                self.cld();
-                self.pc += 1;
-            }
-            0xe0 => {   // CPX IMM
-                self.cpx(memory, memory.get_immediate(self.pc));
-                self.pc += 2;
-            }
-            0xee => {   // INC ABS
-                self.inc(memory, memory.get_absolute(self.pc));
-                self.pc += 3;
-                self.cycles += 6;
-            }
-            0xf0 => {   // BEQ REL
-                self.beq(memory.get_relative(self.pc));
-                self.pc += 2;
-            }
-            _ => println!("Unexpected OP code (0x{:02x})", opcode)
         }
     }
 
-    fn add_branch_cycles(&mut self, address: u16) {
+    /// Takes a branch to the given address, PC must point to the instruction following the branch.
+    /// The CPU reads the next opcode while adding the offset, then reads the target with the
+    /// unfixed high byte when it crosses a page.
+    fn branch<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        bus.read(self.pc);
         self.cycles += 1;
 
-        if (self.pc & 0xff00) != (address & 0xff00) {
+        if is_page_crossed(self.pc, address) {
+            bus.read((self.pc & 0xff00) | (address & 0x00ff));
             self.cycles += 1;
         }
+        self.pc = address;
     }
 
     /// Update the zero and negative flags according to the provided value
@@ -488,10 +468,46 @@ impl Cpu {
     }
 
     // region Interrupts
-    fn handle_nmi(&mut self, memory: &mut Memory) {
-        self.stack_push16(memory, self.pc);
-        self.stack_push8(memory, self.p);
-        self.pc = (memory.read(0xfffb) as u16) << 8 | memory.read(0xfffa) as u16;
+    /// Sets the level of the IRQ line for the given source (IRQ_APU_FRAME, IRQ_DMC, IRQ_MAPPER), the
+    /// IRQ is level-triggered and fires as long as a source asserts it and interrupts are enabled
+    pub fn set_irq_line(&mut self, source: u8, level: bool) {
+        if level {
+            self.irq_lines |= source;
+        } else {
+            self.irq_lines &= !source;
+        }
+    }
+
+    /// Runs the sequence of an NMI or an IRQ, the opcode at PC is read twice and discarded before
+    /// the interrupt sequence
+    fn hardware_interrupt<B: Bus>(&mut self, bus: &mut B, vector: u16) {
+        bus.read(self.pc);
+        bus.read(self.pc);
+        self.interrupt(bus, vector, false);
+        self.cycles += INTERRUPT_CYCLES;
+    }
+
+    /// Runs the interrupt sequence, an NMI detected before the vector fetch hijacks the vector of an
+    /// IRQ or a BRK (the B flag pushed by the BRK is kept)
+    // https://www.nesdev.org/wiki/CPU_interrupts#Interrupt_hijacking
+    fn interrupt<B: Bus>(&mut self, bus: &mut B, vector: u16, brk: bool) {
+        self.stack_push16(bus, self.pc);
+
+        // B only exists in the copy of the flags pushed on the stack
+        let flags = if brk { self.p | FLAG_B | FLAG_U } else { (self.p & !FLAG_B) | FLAG_U };
+        self.stack_push8(bus, flags);
+
+        self.set_status(FLAG_INTERRUPT_DISABLE, true);
+        self.irq_inhibit = true;
+
+        self.nmi_pending |= bus.take_nmi_edge();
+        let vector = if vector == IRQ_VECTOR && self.nmi_pending {
+            self.nmi_pending = false;
+            NMI_VECTOR
+        } else {
+            vector
+        };
+        self.pc = bus.read16(vector);
     }
     // endregion
 
@@ -502,9 +518,9 @@ impl Cpu {
 
     fn set_status(&mut self, flag: u8, status: bool) {
         if status {
-            self.p = self.p | flag
+            self.p |= flag;
         } else {
-            self.p = self.p & !flag
+            self.p &= !flag;
         }
     }
 
@@ -526,133 +542,145 @@ impl Cpu {
     // endregion
 
     // region Stack control
-    fn stack_push8(&mut self, memory: &mut Memory, val: u8) {
+    fn stack_push8<B: Bus>(&mut self, bus: &mut B, val: u8) {
         let address = 0x0100 | (self.s as u16);
-        memory.write(address, val);
+        bus.write(address, val);
 
         let n = self.s.wrapping_sub(1);
         self.s = n;
     }
 
-    fn stack_pop8(&mut self, memory: &Memory) -> u8 {
+    fn stack_pop8<B: Bus>(&mut self, bus: &mut B) -> u8 {
         let n = self.s.wrapping_add(1);
         self.s = n;
 
         // The stack page exists from 0x0100 to 0x01FF
         let address = 0x0100 | (self.s as u16);
-        return memory.read(address);
+        return bus.read(address);
     }
 
-    fn stack_push16(&mut self, memory: &mut Memory, val: u16) {
+    fn stack_push16<B: Bus>(&mut self, bus: &mut B, val: u16) {
         let hi = (val >> 8) as u8;
-        self.stack_push8(memory, hi);
+        self.stack_push8(bus, hi);
 
         let lo = (val & 0x00ff) as u8;
-        self.stack_push8(memory, lo);
+        self.stack_push8(bus, lo);
+    }
+
+    /// Reads the top of the stack without moving the stack pointer, the pull instructions do it
+    /// while incrementing the stack pointer
+    fn stack_dummy_read<B: Bus>(&mut self, bus: &mut B) {
+        bus.read(0x0100 | (self.s as u16));
     }
 
-    fn stack_pop16(&mut self, memory: &Memory) -> u16 {
-        let lo = self.stack_pop8(memory) as u16;
-        let hi = self.stack_pop8(memory) as u16;
+    fn stack_pop16<B: Bus>(&mut self, bus: &mut B) -> u16 {
+        let lo = self.stack_pop8(bus) as u16;
+        let hi = self.stack_pop8(bus) as u16;
         (hi << 8) | lo
     }
     // endregion
 
     // region Operations
-    fn adc(&mut self, memory: &Memory, address: u16) {
-        self.add(memory.read(address));
+    fn adc<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        self.add(bus.read(address));
     }
 
     fn add(&mut self, val: u8) {
-        let sum = self.a + val + self.get_carry();
-        self.set_status(FLAG_OVERFLOW, (!(self.a ^ val) & (self.a ^ sum) & 0x80) > 0);
-        self.a = sum;
+        let sum = self.a as u16 + val as u16 + self.get_carry() as u16;
+        let result = sum as u8;
+        self.set_carry(sum > 0xff);
+        self.set_status(FLAG_OVERFLOW, (!(self.a ^ val) & (self.a ^ result) & 0x80) > 0);
+        self.a = result;
         self.update_sz(self.a);
     }
 
-    fn and(&mut self, memory: &Memory, address: u16) {
-        let val = memory.read(address);
+    fn and<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let val = bus.read(address);
         self.a &= val;
         self.update_sz(self.a);
     }
 
-    fn asl(&mut self, memory: &mut Memory, address: u16) {
-        let val = memory.read(address);
+    fn asl<B: Bus>(&mut self, bus: &mut B, address: u16) -> u8 {
+        let val = read_modify(bus, address);
         self.set_carry(val & 0x80 != 0);
 
-        let n = (val << 1) & 0xff;
-        memory.write(address, n);
+        let n = val << 1;
+        bus.write(address, n);
 
         self.update_sz(n);
+        n
     }
 
     fn asl_akk(&mut self) {
         let val = self.a;
         self.set_carry(val & 0x80 != 0);
 
-        let n = (val << 1) & 0xff;
+        let n = val << 1;
         self.a = n;
 
         self.update_sz(n);
     }
 
-    fn bcs(&mut self, address: u16) {
+    fn bcc<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        if !self.get_status(FLAG_CARRY) {
+            self.branch(bus, address);
+        }
+    }
+
+    fn bcs<B: Bus>(&mut self, bus: &mut B, address: u16) {
         if self.get_status(FLAG_CARRY) {
-            self.add_branch_cycles( address);
-            self.pc = address;
+            self.branch(bus, address);
         }
     }
 
-    fn beq(&mut self, address: u16) {
+    fn beq<B: Bus>(&mut self, bus: &mut B, address: u16) {
         if self.get_status(FLAG_ZERO) {
-            self.add_branch_cycles(address);
-            self.pc = address;
+            self.branch(bus, address);
         }
     }
 
-    fn bit(&mut self, memory: &Memory, address: u16) {
-        let val = memory.read(address);
+    fn bit<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let val = bus.read(address);
         self.set_status(FLAG_NEGATIVE, val & 0x80 != 0);
         self.set_status(FLAG_OVERFLOW, (val >> 0x06 & 0x01) == 1);
         let f = self.a & val;
         self.set_status(FLAG_ZERO, f == 0)
     }
 
-    fn bmi(&mut self, address: u16) {
+    fn bmi<B: Bus>(&mut self, bus: &mut B, address: u16) {
         if self.get_status(FLAG_NEGATIVE) {
-            self.add_branch_cycles(address);
-            self.pc = address;
+            self.branch(bus, address);
         }
     }
 
-    fn bne(&mut self, address: u16) {
-        if self.get_status(FLAG_ZERO) { // TODO Check if !self or self
-            self.add_branch_cycles(address);
-            self.pc = address;
+    fn bne<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        if !self.get_status(FLAG_ZERO) {
+            self.branch(bus, address);
         }
     }
 
-    fn bpl(&mut self, address: u16) {
-        if !self.get_status(FLAG_NEGATIVE) {    // TODO Check if !self or self
-            self.add_branch_cycles(address);
-            self.pc = address;
+    fn bpl<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        if !self.get_status(FLAG_NEGATIVE) {
+            self.branch(bus, address);
         }
     }
 
-    fn brk(&mut self, memory: &mut Memory) {
-        let pc = self.pc + 1;
-        self.stack_push16( memory, pc);
-
-        self.set_status(FLAG_B, true);
-
-        let flags = self.p | 0x10;
-        self.stack_push8(memory, flags);
+    fn brk<B: Bus>(&mut self, bus: &mut B) {
+        // BRK skips the padding byte following the opcode
+        self.pc = self.pc.wrapping_add(1);
+        self.interrupt(bus, IRQ_VECTOR, true);
+    }
 
-        self.set_status(FLAG_INTERRUPT_DISABLE, true);
+    fn bvc<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        if !self.get_status(FLAG_OVERFLOW) {
+            self.branch(bus, address);
+        }
+    }
 
-        let lo = memory.read(0xFFFE) as u16;
-        let hi = memory.read(0xFFFF) as u16;
-        self.pc = (hi << 8) | lo;
+    fn bvs<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        if self.get_status(FLAG_OVERFLOW) {
+            self.branch(bus, address);
+        }
     }
 
     fn clc(&mut self) {
@@ -671,27 +699,35 @@ impl Cpu {
         self.set_status(FLAG_OVERFLOW, false)
     }
 
-    fn cmp(&mut self, memory: &Memory, address: u16) {
-        let val = memory.read(address);
+    fn cmp<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let val = bus.read(address);
         let n = self.a.wrapping_sub(val);
         self.set_carry(self.a >= val);
         self.update_sz(n);
     }
 
-    fn cpx(&mut self, memory: &Memory, address: u16) {
-        let val = memory.read(address);
+    fn cpx<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let val = bus.read(address);
         let n = self.x.wrapping_sub(val);
         self.update_sz(n);
         self.set_carry(self.x >= val);
     }
 
-    fn cpy(&mut self, memory: &Memory, address: u16) {
-        let val = memory.read(address);
+    fn cpy<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let val = bus.read(address);
         let n = self.y.wrapping_sub(val);
         self.update_sz(n);
         self.set_carry(self.y >= val);
     }
 
+    fn dec<B: Bus>(&mut self, bus: &mut B, address: u16) -> u8 {
+        let val = read_modify(bus, address);
+        let n = val.wrapping_sub(1);
+        bus.write(address, n);
+        self.update_sz(n);
+        n
+    }
+
     fn dex(&mut self) {
         let n = self.x.wrapping_sub(1);
         self.x = n;
@@ -704,11 +740,18 @@ impl Cpu {
         self.update_sz(n);
     }
 
-    fn inc(&mut self, memory: &mut Memory, address: u16) {
-        let val = memory.read(address);
+    fn eor<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let val = bus.read(address);
+        self.a ^= val;
+        self.update_sz(self.a);
+    }
+
+    fn inc<B: Bus>(&mut self, bus: &mut B, address: u16) -> u8 {
+        let val = read_modify(bus, address);
         let n = val.wrapping_add(1);
-        memory.write(address, n);
+        bus.write(address, n);
         self.update_sz(n);
+        n
     }
 
     fn inx(&mut self) {
@@ -727,38 +770,44 @@ impl Cpu {
         self.pc = address;
     }
 
-    fn jsr(&mut self, memory: &mut Memory, address: u16, offset: u16) {
-        let ret_address = self.pc + offset;
-        self.stack_push16(memory, ret_address);
-        self.pc = address;
+    fn jsr<B: Bus>(&mut self, bus: &mut B) {
+        // PC already points to the next instruction, the return address pushed is the last byte of
+        // JSR, the high byte of the target, which is only fetched once the return address is pushed
+        let ret_address = self.pc.wrapping_sub(1);
+        let lo = bus.read(self.pc.wrapping_sub(2));
+        self.stack_dummy_read(bus);
+        self.stack_push16(bus, ret_address);
+        let hi = bus.read(ret_address);
+        self.pc = ((hi as u16) << 8) | lo as u16;
     }
 
-    fn lda(&mut self, memory: &Memory, address: u16) {
-        let val = memory.read(address);
+    fn lda<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let val = bus.read(address);
         self.a = val;
         self.update_sz(val);
     }
 
-    fn ldx(&mut self, memory: &Memory, address: u16) {
-        let val = memory.read(address);
+    fn ldx<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let val = bus.read(address);
         self.x = val;
         self.update_sz(val)
     }
 
-    fn ldy(&mut self, memory: &Memory, address: u16) {
-        let val = memory.read(address);
+    fn ldy<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let val = bus.read(address);
         self.y = val;
         self.update_sz(val)
     }
 
-    fn lsr(&mut self, memory: &mut Memory, address: u16) {
-        let val = memory.read(address);
+    fn lsr<B: Bus>(&mut self, bus: &mut B, address: u16) -> u8 {
+        let val = read_modify(bus, address);
 
         self.set_carry(val & 0x01 == 1);
         let n = val >> 1;
         self.update_sz(n);
 
-        memory.write(address, n);
+        bus.write(address, n);
+        n
     }
 
     fn lsr_akk(&mut self) {
@@ -773,83 +822,100 @@ impl Cpu {
 
     fn nop(&mut self) {}
 
-    fn ora(&mut self, memory: &Memory, address: u16) {
-        let val = memory.read(address);
+    /// Unofficial NOP with an operand, the operand is read and discarded
+    fn nop_read<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        bus.read(address);
+    }
+
+    fn ora<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let val = bus.read(address);
         let na = self.a | val;
         self.a = na;
         self.update_sz(na);
     }
 
-    fn php(&mut self, memory: &mut Memory) {
-        self.stack_push8(memory, self.p | 0x10);
+    fn pha<B: Bus>(&mut self, bus: &mut B) {
+        self.stack_push8(bus, self.a);
     }
 
-    fn pla(&mut self, memory: &mut Memory) {
-        let rv = self.stack_pop8(memory);
+    fn php<B: Bus>(&mut self, bus: &mut B) {
+        self.stack_push8(bus, self.p | FLAG_B | FLAG_U);
+    }
+
+    fn pla<B: Bus>(&mut self, bus: &mut B) {
+        self.stack_dummy_read(bus);
+        let rv = self.stack_pop8(bus);
         self.a = rv;
         self.update_sz(rv);
     }
 
-    pub fn plp(&mut self, memory: &mut Memory) {
-        let p = self.stack_pop8(memory) & 0xef | 0x20;
+    pub fn plp<B: Bus>(&mut self, bus: &mut B) {
+        self.stack_dummy_read(bus);
+        let p = self.stack_pop8(bus) & 0xef | 0x20;
         self.p = p;
     }
 
-    fn rol(&mut self, memory: &mut Memory, address: u16) {
-        let val =  memory.read(address);
+    fn rol<B: Bus>(&mut self, bus: &mut B, address: u16) -> u8 {
+        let val = read_modify(bus, address);
 
-        let n = (val << 1) | (self.get_carry() as u8);
+        let n = (val << 1) | self.get_carry();
         self.set_carry(val & 0x80 != 0);
         self.update_sz(n);
 
-        memory.write(address, n);
+        bus.write(address, n);
+        n
     }
 
     fn rol_akk(&mut self) {
         let val = self.a;
 
-        let n = (val << 1) | (self.get_carry() as u8);
+        let n = (val << 1) | self.get_carry();
         self.set_carry(val & 0x80 != 0);
         self.update_sz(n);
 
         self.a = n;
     }
 
-    pub fn ror(&mut self, memory: &mut Memory, address: u16) {
-        let val = memory.read(address);
+    pub fn ror<B: Bus>(&mut self, bus: &mut B, address: u16) -> u8 {
+        let val = read_modify(bus, address);
 
-        let n = (val >> 1) | ((self.get_carry() as u8) << 7);
+        let n = (val >> 1) | (self.get_carry() << 7);
         self.set_carry(val & 0x01 == 1);
         self.update_sz(n);
 
-        memory.write(address, n);
+        bus.write(address, n);
+        n
     }
 
     pub fn ror_akk(&mut self) {
         let val = self.a;
 
-        let n = (val >> 1) | ((self.get_carry() as u8) << 7);
+        let n = (val >> 1) | (self.get_carry() << 7);
         self.set_carry(val & 0x01 == 1);
         self.update_sz(n);
 
         self.a = n;
     }
 
-    fn rti(&mut self, memory: &Memory) {
-        let flags = self.stack_pop8(memory) & 0xef | 0x20;
+    fn rti<B: Bus>(&mut self, bus: &mut B) {
+        self.stack_dummy_read(bus);
+        let flags = self.stack_pop8(bus) & 0xef | 0x20;
         self.p = flags;
 
-        let ret_addr = self.stack_pop16(memory);
+        let ret_addr = self.stack_pop16(bus);
         self.pc = ret_addr;
     }
 
-    fn rts(&mut self, memory: &Memory) {
-        let ret_addr = self.stack_pop16(memory);
-        self.pc = ret_addr + 1;
+    fn rts<B: Bus>(&mut self, bus: &mut B) {
+        self.stack_dummy_read(bus);
+        let ret_addr = self.stack_pop16(bus);
+        // The CPU reads the return address while incrementing it
+        bus.read(ret_addr);
+        self.pc = ret_addr.wrapping_add(1);
     }
 
-    fn sbc(&mut self, memory: &Memory, address: u16) {
-        return self.add(!memory.read(address));
+    fn sbc<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        return self.add(!bus.read(address));
     }
 
     fn sec(&mut self) {
@@ -864,16 +930,16 @@ impl Cpu {
         self.set_status(FLAG_INTERRUPT_DISABLE, true);
     }
 
-    fn sta(&mut self, memory: &mut Memory, address: u16) {
-        memory.write(address, self.a);
+    fn sta<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        bus.write(address, self.a);
     }
 
-    fn stx(&mut self, memory: &mut Memory, address: u16) {
-        memory.write(address, self.x);
+    fn stx<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        bus.write(address, self.x);
     }
 
-    fn sty(&mut self, memory: &mut Memory, address: u16) {
-        memory.write(address, self.y);
+    fn sty<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        bus.write(address, self.y);
     }
 
     fn tax(&mut self) {
@@ -911,4 +977,431 @@ impl Cpu {
     }
 
     // endregion
+
+    // region Unofficial operations
+    // https://www.nesdev.org/wiki/Programming_with_unofficial_opcodes
+    // https://www.masswerk.at/nowgobang/2021/6502-illegal-opcodes
+
+    /// Magic constant of the unstable ANE/LXA opcodes, it depends on the chip and its temperature
+    const UNSTABLE_MAGIC: u8 = 0xee;
+
+    fn ahx<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let val = self.a & self.x;
+        self.store_high_and(bus, address, self.y, val);
+    }
+
+    fn alr<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        self.and(bus, address);
+        self.lsr_akk();
+    }
+
+    fn anc<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        self.and(bus, address);
+        self.set_carry(self.a & 0x80 != 0);
+    }
+
+    fn arr<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        self.and(bus, address);
+        self.ror_akk();
+
+        let bit6 = self.a & 0x40 != 0;
+        let bit5 = self.a & 0x20 != 0;
+        self.set_carry(bit6);
+        self.set_status(FLAG_OVERFLOW, bit6 ^ bit5);
+    }
+
+    fn axs<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let val = bus.read(address);
+        let ax = self.a & self.x;
+        let n = ax.wrapping_sub(val);
+        self.set_carry(ax >= val);
+        self.x = n;
+        self.update_sz(n);
+    }
+
+    fn dcp<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let val = self.dec(bus, address);
+        self.set_carry(self.a >= val);
+        self.update_sz(self.a.wrapping_sub(val));
+    }
+
+    fn isc<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let val = self.inc(bus, address);
+        self.add(!val);
+    }
+
+    fn las<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let n = bus.read(address) & self.s;
+        self.a = n;
+        self.x = n;
+        self.s = n;
+        self.update_sz(n);
+    }
+
+    fn lax<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        self.lda(bus, address);
+        self.x = self.a;
+    }
+
+    fn lxa<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let n = (self.a | Cpu::UNSTABLE_MAGIC) & bus.read(address);
+        self.a = n;
+        self.x = n;
+        self.update_sz(n);
+    }
+
+    fn rla<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        self.a &= self.rol(bus, address);
+        self.update_sz(self.a);
+    }
+
+    fn rra<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let val = self.ror(bus, address);
+        self.add(val);
+    }
+
+    fn sax<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        bus.write(address, self.a & self.x);
+    }
+
+    fn shx<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        self.store_high_and(bus, address, self.y, self.x);
+    }
+
+    fn shy<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        self.store_high_and(bus, address, self.x, self.y);
+    }
+
+    fn slo<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        self.a |= self.asl(bus, address);
+        self.update_sz(self.a);
+    }
+
+    fn sre<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        self.a ^= self.lsr(bus, address);
+        self.update_sz(self.a);
+    }
+
+    fn stp(&mut self) {
+        // The CPU stays stuck on the opcode
+        self.pc = self.pc.wrapping_sub(1);
+        self.halted = true;
+    }
+
+    fn tas<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        self.s = self.a & self.x;
+        self.store_high_and(bus, address, self.y, self.s);
+    }
+
+    fn xaa<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        let n = (self.a | Cpu::UNSTABLE_MAGIC) & self.x & bus.read(address);
+        self.a = n;
+        self.update_sz(n);
+    }
+
+    /// Common behaviour of the unstable SHA/SHX/SHY/TAS stores: the value is ANDed with the high
+    /// byte of the base address plus one, and on a page crossing that value also replaces the high
+    /// byte of the effective address
+    fn store_high_and<B: Bus>(&mut self, bus: &mut B, address: u16, index: u8, val: u8) {
+        let base = address.wrapping_sub(index as u16);
+        let n = val & ((base >> 8) as u8).wrapping_add(1);
+
+        let address = if is_page_crossed(base, address) {
+            ((n as u16) << 8) | (address & 0x00ff)
+        } else {
+            address
+        };
+
+        bus.write(address, n);
+    }
+    // endregion
+}
+
+/// Reads the operand of a read-modify-write instruction, the unmodified value is written back while
+/// the result is computed (some mappers see both writes)
+fn read_modify<B: Bus>(bus: &mut B, address: u16) -> u8 {
+    let val = bus.read(address);
+    bus.write(address, val);
+    val
+}
+
+/// True if both addresses are not in the same page
+fn is_page_crossed(a: u16, b: u16) -> bool {
+    (a & 0xff00) != (b & 0xff00)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::RamBus;
+
+    const PROGRAM_START: u16 = 0x8000;
+    const IRQ_HANDLER: u16 = 0x9000;
+    const NMI_HANDLER: u16 = 0xa000;
+
+    /// Creates a CPU reset on a memory with the given program at $8000
+    fn cpu_with(program: &[u8]) -> (Cpu, RamBus) {
+        let mut memory = RamBus::new();
+        for (i, b) in program.iter().enumerate() {
+            memory.write(PROGRAM_START + i as u16, *b);
+        }
+        memory.write(RESET_VECTOR, PROGRAM_START as u8);
+        memory.write(RESET_VECTOR + 1, (PROGRAM_START >> 8) as u8);
+        memory.write(IRQ_VECTOR, IRQ_HANDLER as u8);
+        memory.write(IRQ_VECTOR + 1, (IRQ_HANDLER >> 8) as u8);
+        memory.write(NMI_VECTOR, NMI_HANDLER as u8);
+        memory.write(NMI_VECTOR + 1, (NMI_HANDLER >> 8) as u8);
+
+        let cpu = Cpu::new(&mut memory);
+        (cpu, memory)
+    }
+
+    #[test]
+    fn every_cycle_accesses_the_bus() {
+        for opcode in 0..=0xff {
+            if OPCODES[opcode as usize].instruction == Instruction::STP {
+                continue;
+            }
+            // With and without page crossings, and with all the branches taken and not taken
+            for (index, p) in [(0x00, 0x00), (0xff, 0xff)] {
+                let (mut cpu, mut memory) = cpu_with(&[opcode, 0xf0, 0x12]);
+                memory.write(0x00f0, 0xf0);
+                memory.write(0x00f1, 0x12);
+                cpu.set_x(index);
+                cpu.set_y(index);
+                cpu.set_p(p);
+                memory.clear_accesses();
+
+                let cycles = cpu.step(&mut memory);
+                assert_eq!(memory.get_accesses().len(), cycles as usize, "opcode {:02x}", opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn adc_overflow_and_carry() {
+        // ADC #$50, ADC #$90
+        let (mut cpu, mut memory) = cpu_with(&[0x69, 0x50, 0x69, 0x90]);
+        cpu.set_a(0x50);
+
+        cpu.step(&mut memory);
+        assert_eq!(cpu.get_a(), 0xa0);
+        assert!(cpu.get_status(FLAG_OVERFLOW));
+        assert!(cpu.get_status(FLAG_NEGATIVE));
+        assert!(!cpu.get_status(FLAG_CARRY));
+
+        cpu.set_a(0xd0);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.get_a(), 0x60);
+        assert!(cpu.get_status(FLAG_OVERFLOW));
+        assert!(cpu.get_status(FLAG_CARRY));
+    }
+
+    #[test]
+    fn sbc_overflow_and_borrow() {
+        // SEC, SBC #$b0, SEC, SBC #$70
+        let (mut cpu, mut memory) = cpu_with(&[0x38, 0xe9, 0xb0, 0x38, 0xe9, 0x70]);
+        cpu.set_a(0x50);
+
+        cpu.step(&mut memory);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.get_a(), 0xa0);
+        assert!(cpu.get_status(FLAG_OVERFLOW));
+        // The carry is cleared on a borrow
+        assert!(!cpu.get_status(FLAG_CARRY));
+
+        cpu.set_a(0xd0);
+        cpu.step(&mut memory);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.get_a(), 0x60);
+        assert!(cpu.get_status(FLAG_OVERFLOW));
+        assert!(cpu.get_status(FLAG_CARRY));
+    }
+
+    #[test]
+    fn php_and_brk_push_the_b_flag() {
+        // PHP, BRK
+        let (mut cpu, mut memory) = cpu_with(&[0x08, 0x00]);
+        cpu.set_p(FLAG_CARRY);
+
+        cpu.step(&mut memory);
+        assert_eq!(memory.peek(0x01fd), FLAG_CARRY | FLAG_B | FLAG_U);
+        assert_eq!(cpu.get_s(), 0xfc);
+
+        assert_eq!(cpu.step(&mut memory), 7);
+        assert_eq!(cpu.get_pc(), IRQ_HANDLER);
+        // BRK skips its padding byte
+        assert_eq!(memory.peek(0x01fc), 0x80);
+        assert_eq!(memory.peek(0x01fb), 0x03);
+        assert_eq!(memory.peek(0x01fa), FLAG_CARRY | FLAG_B | FLAG_U);
+        assert!(cpu.get_status(FLAG_INTERRUPT_DISABLE));
+        assert!(!cpu.get_status(FLAG_B));
+    }
+
+    #[test]
+    fn irq_does_not_push_the_b_flag() {
+        // NOP
+        let (mut cpu, mut memory) = cpu_with(&[0xea]);
+        cpu.set_p(FLAG_CARRY);
+        cpu.step(&mut memory);
+        cpu.set_irq_line(IRQ_MAPPER, true);
+
+        assert_eq!(cpu.step(&mut memory), 7);
+        assert_eq!(cpu.get_pc(), IRQ_HANDLER);
+        assert_eq!(memory.peek(0x01fd), 0x80);
+        assert_eq!(memory.peek(0x01fc), 0x01);
+        assert_eq!(memory.peek(0x01fb), FLAG_CARRY | FLAG_U);
+    }
+
+    #[test]
+    fn jmp_indirect_wraps_in_the_page() {
+        // JMP ($10ff)
+        let (mut cpu, mut memory) = cpu_with(&[0x6c, 0xff, 0x10]);
+        memory.write(0x10ff, 0x34);
+        memory.write(0x1000, 0x12);
+        memory.write(0x1100, 0x56);
+
+        cpu.step(&mut memory);
+        assert_eq!(cpu.get_pc(), 0x1234);
+    }
+
+    #[test]
+    fn branch_cycles() {
+        // BNE +2, BNE +2, then BNE +$7f at $80f0
+        let (mut cpu, mut memory) = cpu_with(&[0xd0, 0x02, 0x00, 0x00, 0xd0, 0x02]);
+        memory.write(0x80f0, 0xd0);
+        memory.write(0x80f1, 0x7f);
+
+        // Taken in the same page
+        assert_eq!(cpu.step(&mut memory), 3);
+        assert_eq!(cpu.get_pc(), 0x8004);
+
+        // Not taken
+        cpu.set_status(FLAG_ZERO, true);
+        assert_eq!(cpu.step(&mut memory), 2);
+        assert_eq!(cpu.get_pc(), 0x8006);
+
+        // Taken to the next page
+        cpu.set_status(FLAG_ZERO, false);
+        cpu.set_pc(0x80f0);
+        assert_eq!(cpu.step(&mut memory), 4);
+        assert_eq!(cpu.get_pc(), 0x8171);
+    }
+
+    #[test]
+    fn irq_is_delayed_after_cli() {
+        // CLI, NOP
+        let (mut cpu, mut memory) = cpu_with(&[0x58, 0xea]);
+        cpu.set_irq_line(IRQ_MAPPER, true);
+
+        cpu.step(&mut memory);
+        assert!(!cpu.get_status(FLAG_INTERRUPT_DISABLE));
+        // The instruction following CLI runs before the IRQ
+        cpu.step(&mut memory);
+        assert_eq!(cpu.get_pc(), 0x8002);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.get_pc(), IRQ_HANDLER);
+    }
+
+    #[test]
+    fn irq_is_taken_after_sei() {
+        // CLI, NOP, SEI
+        let (mut cpu, mut memory) = cpu_with(&[0x58, 0xea, 0x78]);
+        cpu.step(&mut memory);
+        cpu.step(&mut memory);
+
+        cpu.step(&mut memory);
+        assert!(cpu.get_status(FLAG_INTERRUPT_DISABLE));
+        // The IRQ polled during SEI still sees the interrupts enabled
+        cpu.set_irq_line(IRQ_MAPPER, true);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.get_pc(), IRQ_HANDLER);
+        assert_eq!(memory.peek(0x01fb) & FLAG_INTERRUPT_DISABLE, FLAG_INTERRUPT_DISABLE);
+    }
+
+    #[test]
+    fn irq_is_delayed_after_plp() {
+        // PLP, NOP
+        let (mut cpu, mut memory) = cpu_with(&[0x28, 0xea]);
+        memory.write(0x01fe, FLAG_U);
+        cpu.set_irq_line(IRQ_MAPPER, true);
+
+        cpu.step(&mut memory);
+        assert!(!cpu.get_status(FLAG_INTERRUPT_DISABLE));
+        cpu.step(&mut memory);
+        assert_eq!(cpu.get_pc(), 0x8002);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.get_pc(), IRQ_HANDLER);
+    }
+
+    #[test]
+    fn nmi_is_taken_on_the_next_step() {
+        // NOP, NOP
+        let (mut cpu, mut memory) = cpu_with(&[0xea, 0xea]);
+        memory.clear_accesses();
+        memory.set_nmi_edge_at(1);
+
+        cpu.step(&mut memory);
+        assert_eq!(cpu.get_pc(), 0x8001);
+        assert_eq!(cpu.step(&mut memory), 7);
+        assert_eq!(cpu.get_pc(), NMI_HANDLER);
+    }
+
+    #[test]
+    fn nmi_hijacks_brk() {
+        // BRK
+        let (mut cpu, mut memory) = cpu_with(&[0x00]);
+        memory.write(NMI_HANDLER, 0xea);
+        memory.clear_accesses();
+        // The NMI is raised while the return address is pushed
+        memory.set_nmi_edge_at(3);
+
+        assert_eq!(cpu.step(&mut memory), 7);
+        assert_eq!(cpu.get_pc(), NMI_HANDLER);
+        assert_eq!(memory.peek(0x01fd), 0x80);
+        assert_eq!(memory.peek(0x01fc), 0x02);
+        // The B flag pushed tells the handler that a BRK was hijacked
+        assert_ne!(memory.peek(0x01fb) & FLAG_B, 0);
+
+        // The NMI is not serviced again
+        cpu.step(&mut memory);
+        assert_eq!(cpu.get_pc(), NMI_HANDLER + 1);
+    }
+
+    #[test]
+    fn nmi_hijacks_irq() {
+        // NOP
+        let (mut cpu, mut memory) = cpu_with(&[0xea]);
+        memory.write(NMI_HANDLER, 0xea);
+        cpu.set_p(FLAG_U);
+        cpu.set_irq_line(IRQ_MAPPER, true);
+        cpu.step(&mut memory);
+        memory.clear_accesses();
+        // The NMI is raised while the flags are pushed
+        memory.set_nmi_edge_at(5);
+
+        assert_eq!(cpu.step(&mut memory), 7);
+        assert_eq!(cpu.get_pc(), NMI_HANDLER);
+        assert_eq!(memory.peek(0x01fd), 0x80);
+        assert_eq!(memory.peek(0x01fc), 0x01);
+        assert_eq!(memory.peek(0x01fb) & FLAG_B, 0);
+
+        // The NMI is not serviced again, the IRQ is masked by the interrupt disable flag
+        cpu.step(&mut memory);
+        assert_eq!(cpu.get_pc(), NMI_HANDLER + 1);
+    }
+
+    #[test]
+    fn nmi_after_the_vector_fetch_does_not_hijack_brk() {
+        // BRK
+        let (mut cpu, mut memory) = cpu_with(&[0x00]);
+        memory.write(IRQ_HANDLER, 0xea);
+        memory.clear_accesses();
+        memory.set_nmi_edge_at(6);
+
+        cpu.step(&mut memory);
+        assert_eq!(cpu.get_pc(), IRQ_HANDLER);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.get_pc(), NMI_HANDLER);
+    }
 }
@@ -1,13 +1,24 @@
 mod rom_file;
 mod cpu;
+mod opcodes;
 mod ppu;
+mod ppu_bus;
 mod memory;
+mod bus;
 mod controller;
+mod apu;
+mod mapper;
+mod palette;
+mod region;
 mod nes_debug;
 
+#[cfg(test)]
+mod tests;
+
 use crate::memory::{Memory, PPU_CTRL};
-use crate::cpu::Cpu;
-use crate::ppu::Ppu;
+use crate::cpu::{Cpu, IRQ_APU_FRAME, IRQ_MAPPER};
+use crate::controller::*;
+use crate::ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
 use crate::rom_file::RomFile;
 use crate::nes_debug::sdl_ppu;
 
@@ -16,13 +27,15 @@ extern crate sdl2;
 use sdl2::pixels::{Color, PixelFormat, PixelFormatEnum};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
-use std::time::Duration;
-use log::{LevelFilter, Level, log_enabled, debug, error};
+use std::time::{Duration, Instant};
+use log::{LevelFilter, Level, log_enabled, debug, error, trace};
 use sdl2::EventPump;
 use sdl2::render::{Canvas, Texture, TextureAccess, TextureCreator};
 use std::any::Any;
 use sdl2::rect::Rect;
 use crate::nes_debug::sdl_ppu::debug_palette;
+use crate::palette::{Palette, NtscParameters};
+use crate::region::Region;
 
 pub fn main() {
     // Initialize logger
@@ -43,11 +56,10 @@ pub fn main() {
     canvas.set_draw_color(Color::RGB(0, 0, 0));
     canvas.clear();
     canvas.present();
-    let mut framebuffer: [u8; 1024 * 256 * 3];
     let texture_creator = canvas.texture_creator();
-    let mut texture =
+    let mut screen_texture =
         texture_creator
-            .create_texture(PixelFormatEnum::RGB24, TextureAccess::Streaming, 1024, 256)
+            .create_texture(PixelFormatEnum::RGB24, TextureAccess::Streaming, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
             .unwrap();
     let mut debug_chr_texture =
         texture_creator
@@ -55,6 +67,12 @@ pub fn main() {
             .unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
 
+    // Load the palette file, or generate the palette if there is none
+    let palette = Palette::from_file("palettes/nes.pal").unwrap_or_else(|err| {
+        debug!("Palette file not loaded ({}), using the generated NTSC palette", err);
+        Palette::generate(&NtscParameters::default())
+    });
+
     // Load the ROM file
     // let rom_result = RomFile::new("roms/Donkey Kong (World) (Rev A).nes");
     let rom_result = RomFile::new("roms/Super Mario Bros. (World).nes");
@@ -68,31 +86,68 @@ pub fn main() {
             println!("\tCHR size: {0} x 8kB", rom_file.raw_chr_size());
             println!("\tTrainer: {0}", rom_file.has_trainer());
 
-            // Initialize the NES emulation system
-            let mut cpu_mem = Memory::new();
-            cpu_mem.load(&rom_file);
+            // The region given on the command line overrides the one of the header
+            let region = get_region_override().unwrap_or_else(|| rom_file.get_region());
+            println!("\tRegion: {0:?}", region);
 
-            let mut cpu = Cpu::new(&cpu_mem);
+            println!("\tMapper: {0}", rom_file.get_mapper_type());
+            let cartridge = match mapper::new_mapper(&rom_file) {
+                Ok(cartridge) => cartridge,
+                Err(err) => {
+                    error!("Cannot load the cartridge: {}", err);
+                    return;
+                }
+            };
 
-            let mut ppu = Ppu::new();
-            ppu.load(&rom_file);
+            // Initialize the NES emulation system
+            let mut cpu_mem = Memory::new(cartridge);
+            cpu_mem.set_region(region);
+
+            let mut cpu = Cpu::new(&mut cpu_mem);
 
             // Run
+            let frame_duration = Duration::from_secs_f64(1.0 / region.get_frame_rate());
+            let mut next_frame = Instant::now();
             'running: loop {
+                handle_user_input(&mut cpu_mem, &mut event_pump);
+
+                // Run the emulation until the PPU completes a frame
+                while !cpu_mem.take_frame_complete() {
+                    // Log the instructions in the nestest format (RUST_LOG=trace)
+                    if log_enabled!(Level::Trace) {
+                        let ppu = cpu_mem.get_ppu();
+                        trace!("{}", nes_debug::trace::trace(&cpu, &cpu_mem, ppu.get_scanline(), ppu.get_dot()));
+                    }
+                    cpu.step(&mut cpu_mem);
+                    if cpu.is_halted() {
+                        error!("CPU halted by a STP opcode at 0x{:04x}", cpu.get_pc());
+                        break 'running;
+                    }
+                    cpu.set_irq_line(IRQ_APU_FRAME, cpu_mem.get_apu_irq());
+                    cpu.set_irq_line(IRQ_MAPPER, cpu_mem.get_mapper_irq());
+                }
+
                 canvas.set_draw_color(Color::RGB(0, 0, 0));
                 canvas.clear();
 
-                handle_user_input(&mut cpu_mem, &mut event_pump);
-                ppu.step(&mut cpu_mem);
-                cpu.step(&mut cpu_mem);
+                // Screen, scaled 2x
+                nes_debug::sdl_ppu::fill_texture_framebuffer(&mut screen_texture, cpu_mem.get_ppu(), |val| palette.get_color(val));
+                canvas.copy(&screen_texture, None, Some(Rect::new(0, 0, 2 * SCREEN_WIDTH as u32, 2 * SCREEN_HEIGHT as u32)));
 
                 // Debug draw
-                nes_debug::sdl_ppu::fill_texture_chr_data(&mut debug_chr_texture, &ppu, debug_palette);
-                canvas.copy(&debug_chr_texture, None, Some(Rect::new(0, 0, 1024, 1024)));
+                nes_debug::sdl_ppu::fill_texture_chr_data(&mut debug_chr_texture, cpu_mem.get_cartridge(), debug_palette);
+                canvas.copy(&debug_chr_texture, None, Some(Rect::new(2 * SCREEN_WIDTH as i32 + 16, 0, 256, 256)));
 
                 canvas.present();
 
-                ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
+                // Wait for the next frame, the emulation catches up if it is late
+                next_frame += frame_duration;
+                let now = Instant::now();
+                if next_frame > now {
+                    ::std::thread::sleep(next_frame - now);
+                } else {
+                    next_frame = now;
+                }
             }
         } else {
             error!("ROM file is not a NES ROM file: {p} ({s})", p=rom_file.file_path, s=rom_file.data.len());
@@ -100,57 +155,50 @@ pub fn main() {
     }
 }
 
+/// Gets the region given on the command line with `--region <ntsc|pal|dendy>`
+fn get_region_override() -> Option<Region> {
+    let args: Vec<String> = std::env::args().collect();
+    let position = args.iter().position(|arg| arg == "--region")?;
+
+    let region = args.get(position + 1).and_then(|name| Region::parse(name));
+    if region.is_none() {
+        error!("Invalid region, expected ntsc, pal or dendy");
+    }
+    region
+}
+
 pub fn handle_user_input(memory: &mut Memory, event_pump: &mut EventPump) {
     for event in event_pump.poll_iter() {
         match event {
             Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                 std::process::exit(0)
             },
-            Event::KeyDown { keycode: Some(Keycode::K), .. } => {    // A
-                // memory.write(CONTROLLER_1_ADDRESS, JOY_BUTTON_A);
-            },
-            Event::KeyDown { keycode: Some(Keycode::L), .. } => {    // B
-                // memory.write(CONTROLLER_1_ADDRESS, JOY_BUTTON_B);
-            },
-            Event::KeyDown { keycode: Some(Keycode::Space), .. } => {    // START
-                // memory.write(CONTROLLER_1_ADDRESS, JOY_START);
-            },
-            Event::KeyDown { keycode: Some(Keycode::Return), .. } => {    // SELECT
-                // memory.write(CONTROLLER_1_ADDRESS, JOY_SELECT);
+            Event::KeyDown { keycode: Some(keycode), .. } => {
+                if let Some(button) = get_controller_button(keycode) {
+                    memory.get_controller(0).set_button(button, true);
+                }
             },
-            Event::KeyDown { keycode: Some(Keycode::Z), .. } => {   // UP
-                // memory.write(CONTROLLER_1_ADDRESS, JOY_UP);
+            Event::KeyUp { keycode: Some(keycode), .. } => {
+                if let Some(button) = get_controller_button(keycode) {
+                    memory.get_controller(0).set_button(button, false);
+                }
             },
-            Event::KeyDown { keycode: Some(Keycode::S), .. } => {   // DOWN
-                // memory.write(CONTROLLER_1_ADDRESS, JOY_DOWN);
-            },
-            Event::KeyDown { keycode: Some(Keycode::Q), .. } => {   // LEFT
-                // memory.write(CONTROLLER_1_ADDRESS, JOY_LEFT);
-            },
-            Event::KeyDown { keycode: Some(Keycode::D), .. } => {   // RIGHT
-                // memory.write(CONTROLLER_1_ADDRESS, JOY_RIGHT);
-            }
             _ => {/* do nothing */}
         }
     }
 }
 
-fn draw<T>(ppu: &Ppu, memory: &Memory, framebuffer: &mut [u8; 1024 * 256 * 3], texture: &mut Texture) {
-    for r in 0..1024 {
-        for col in 0..256 {
-            let tile_nr = ppu.read_vram(0x2000 + (r / 8 * 32) + (col / 8));
-            let tile_attr = ppu.read_vram(0);
-
-            let adr = memory.get_background_pattern_table_address_value() + (tile_nr as u16 * 0x10) + (r % 8) as u16;
-            let pixel = ((ppu.read_vram(adr) >> (7 - (col % 8))) & 1) + (((ppu.read_vram(adr + 8) >> (7 - (col % 8))) & 1) * 2);
-            // framebuffer[(r * 256 * 3) + (col * 3)] = COLORS[pixel];
-            // framebuffer[(r * 256 * 3) + (col * 3) + 1] = COLORS[pixel];
-            // framebuffer[(r * 256 * 3) + (col * 3) + 2] = COLORS[pixel];
-            framebuffer[((r * 256 * 3) + (col * 3)) as usize] = 255;
-            framebuffer[((r * 256 * 3) + (col * 3) + 1) as usize] = 255;
-            framebuffer[((r * 256 * 3) + (col * 3) + 2) as usize] = 255;
-        }
+/// Gets the controller button mapped to the given key
+fn get_controller_button(keycode: Keycode) -> Option<u8> {
+    match keycode {
+        Keycode::K => Some(JOY_BUTTON_A),
+        Keycode::L => Some(JOY_BUTTON_B),
+        Keycode::Space => Some(JOY_START),
+        Keycode::Return => Some(JOY_SELECT),
+        Keycode::Z => Some(JOY_UP),
+        Keycode::S => Some(JOY_DOWN),
+        Keycode::Q => Some(JOY_LEFT),
+        Keycode::D => Some(JOY_RIGHT),
+        _ => None
     }
-
-    texture.update(None, framebuffer, 256 * 3);
 }
@@ -0,0 +1,80 @@
+use crate::mapper::{Mapper, Cartridge};
+use crate::rom_file::Mirroring;
+
+// https://www.nesdev.org/wiki/AxROM
+
+const FLAG_PRG_BANK: u8     = 0b00000111;
+const FLAG_NAMETABLE: u8    = 0b00010000;
+
+/// AxROM (mapper 7), switchable 32kB PRG ROM bank, 8kB of CHR RAM and single-screen mirroring on
+/// either bank of the CIRAM
+pub struct Axrom {
+    cartridge: Cartridge,
+    prg_bank: u8,
+    /// The ROM drives the data bus during the writes to the bank register (ANROM, some AOROM)
+    bus_conflicts: bool,
+}
+
+impl Axrom {
+    pub fn new(mut cartridge: Cartridge, bus_conflicts: bool) -> Axrom {
+        cartridge.nametables.set_mirroring(Mirroring::SINGLE_SCREEN_A);
+
+        Axrom {
+            cartridge,
+            prg_bank: 0,
+            bus_conflicts,
+        }
+    }
+
+    fn read_prg_rom(&self, address: u16) -> u8 {
+        self.cartridge.read_prg_rom(self.prg_bank as usize, 0x8000, address)
+    }
+}
+
+impl Mapper for Axrom {
+    fn get_cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    fn get_cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cartridge
+    }
+
+    fn cpu_peek(&self, address: u16) -> Option<u8> {
+        match address {
+            0x8000..=0xffff => Some(self.read_prg_rom(address)),
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, val: u8) {
+        if address >= 0x8000 {
+            let val = if self.bus_conflicts { val & self.read_prg_rom(address) } else { val };
+
+            self.prg_bank = val & FLAG_PRG_BANK;
+            self.cartridge.nametables.set_mirroring(if (val & FLAG_NAMETABLE) != 0 {
+                Mirroring::SINGLE_SCREEN_B
+            } else {
+                Mirroring::SINGLE_SCREEN_A
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switches_the_prg_bank_and_the_nametable() {
+        let prg_rom = (0..8).flat_map(|bank| vec![bank as u8; 0x8000]).collect();
+        let cartridge = Cartridge::from_memories(prg_rom, Vec::new(), Mirroring::VERTICAL);
+        let mut axrom = Axrom::new(cartridge, false);
+        assert_eq!(axrom.get_mirroring(), Mirroring::SINGLE_SCREEN_A);
+
+        axrom.cpu_write(0x8000, FLAG_NAMETABLE | 0x05);
+        assert_eq!(axrom.cpu_peek(0x8000), Some(5));
+        assert_eq!(axrom.cpu_peek(0xffff), Some(5));
+        assert_eq!(axrom.get_mirroring(), Mirroring::SINGLE_SCREEN_B);
+    }
+}
@@ -0,0 +1,70 @@
+use crate::mapper::{Mapper, Cartridge};
+
+// https://www.nesdev.org/wiki/CNROM
+
+/// CNROM (mapper 3), fixed 16kB or 32kB of PRG ROM (like NROM) and switchable 8kB CHR ROM bank
+pub struct Cnrom {
+    cartridge: Cartridge,
+    chr_bank: u8,
+    /// The ROM drives the data bus during the writes to the bank register
+    bus_conflicts: bool,
+}
+
+impl Cnrom {
+    pub fn new(cartridge: Cartridge, bus_conflicts: bool) -> Cnrom {
+        Cnrom {
+            cartridge,
+            chr_bank: 0,
+            bus_conflicts,
+        }
+    }
+}
+
+impl Mapper for Cnrom {
+    fn get_cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    fn get_cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cartridge
+    }
+
+    fn get_chr_bank(&self, _address: u16) -> (usize, usize) {
+        (self.chr_bank as usize, 0x2000)
+    }
+
+    fn cpu_peek(&self, address: u16) -> Option<u8> {
+        match address {
+            0x8000..=0xffff => Some(self.cartridge.read_prg_rom(0, 0x8000, address)),
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, val: u8) {
+        if address >= 0x8000 {
+            let rom_val = self.cartridge.read_prg_rom(0, 0x8000, address);
+            self.chr_bank = if self.bus_conflicts { val & rom_val } else { val };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom_file::Mirroring;
+    use crate::ppu_bus::PpuBus;
+
+    #[test]
+    fn switches_the_chr_bank() {
+        let chr_rom = (0..4).flat_map(|bank| vec![bank as u8; 0x2000]).collect();
+        let cartridge = Cartridge::from_memories(vec![0xff; 0x8000], chr_rom, Mirroring::VERTICAL);
+        let mut cnrom = Cnrom::new(cartridge, true);
+
+        cnrom.cpu_write(0x8000, 0x02);
+        assert_eq!(cnrom.peek(0x0000), 2);
+        assert_eq!(cnrom.peek(0x1fff), 2);
+        // The bank number wraps around the CHR ROM size
+        cnrom.cpu_write(0x8000, 0x07);
+        assert_eq!(cnrom.peek(0x0000), 3);
+    }
+}
@@ -0,0 +1,76 @@
+use crate::mapper::{Mapper, Cartridge};
+
+// https://www.nesdev.org/wiki/Color_Dreams
+
+const FLAG_PRG_BANK: u8 = 0b00000011;
+const FLAG_CHR_BANK: u8 = 0b11110000;
+
+/// Color Dreams (mapper 11), switchable 32kB PRG ROM and 8kB CHR ROM banks, with bus conflicts. The
+/// register is laid out like GxROM with the PRG and CHR bits swapped, and 4 bits of CHR bank.
+pub struct ColorDreams {
+    cartridge: Cartridge,
+    prg_bank: u8,
+    chr_bank: u8,
+}
+
+impl ColorDreams {
+    pub fn new(cartridge: Cartridge) -> ColorDreams {
+        ColorDreams {
+            cartridge,
+            prg_bank: 0,
+            chr_bank: 0,
+        }
+    }
+
+    fn read_prg_rom(&self, address: u16) -> u8 {
+        self.cartridge.read_prg_rom(self.prg_bank as usize, 0x8000, address)
+    }
+}
+
+impl Mapper for ColorDreams {
+    fn get_cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    fn get_cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cartridge
+    }
+
+    fn get_chr_bank(&self, _address: u16) -> (usize, usize) {
+        (self.chr_bank as usize, 0x2000)
+    }
+
+    fn cpu_peek(&self, address: u16) -> Option<u8> {
+        match address {
+            0x8000..=0xffff => Some(self.read_prg_rom(address)),
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, val: u8) {
+        if address >= 0x8000 {
+            let val = val & self.read_prg_rom(address);
+            self.prg_bank = val & FLAG_PRG_BANK;
+            self.chr_bank = (val & FLAG_CHR_BANK) >> 4;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom_file::Mirroring;
+    use crate::ppu_bus::PpuBus;
+
+    #[test]
+    fn switches_the_prg_and_chr_banks() {
+        let prg_rom = (0..4).flat_map(|bank| vec![0xf0 | bank as u8; 0x8000]).collect();
+        let chr_rom = (0..16).flat_map(|bank| vec![bank as u8; 0x2000]).collect();
+        let mut color_dreams = ColorDreams::new(Cartridge::from_memories(prg_rom, chr_rom, Mirroring::VERTICAL));
+
+        // The bank 0 is mapped, so the PRG bits of the written value are ANDed with 0
+        color_dreams.cpu_write(0x8000, 0xa3);
+        assert_eq!(color_dreams.cpu_peek(0x8000), Some(0xf0));
+        assert_eq!(color_dreams.peek(0x0000), 0x0a);
+    }
+}
@@ -0,0 +1,86 @@
+use crate::mapper::{Mapper, Cartridge};
+
+// https://www.nesdev.org/wiki/GxROM
+
+const FLAG_PRG_BANK: u8 = 0b00110000;
+const FLAG_CHR_BANK: u8 = 0b00000011;
+
+/// GxROM (mapper 66), switchable 32kB PRG ROM and 8kB CHR ROM banks, with bus conflicts
+pub struct Gxrom {
+    cartridge: Cartridge,
+    prg_bank: u8,
+    chr_bank: u8,
+}
+
+impl Gxrom {
+    pub fn new(cartridge: Cartridge) -> Gxrom {
+        Gxrom {
+            cartridge,
+            prg_bank: 0,
+            chr_bank: 0,
+        }
+    }
+
+    fn read_prg_rom(&self, address: u16) -> u8 {
+        self.cartridge.read_prg_rom(self.prg_bank as usize, 0x8000, address)
+    }
+}
+
+impl Mapper for Gxrom {
+    fn get_cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    fn get_cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cartridge
+    }
+
+    fn get_chr_bank(&self, _address: u16) -> (usize, usize) {
+        (self.chr_bank as usize, 0x2000)
+    }
+
+    fn cpu_peek(&self, address: u16) -> Option<u8> {
+        match address {
+            0x8000..=0xffff => Some(self.read_prg_rom(address)),
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, val: u8) {
+        if address >= 0x8000 {
+            let val = val & self.read_prg_rom(address);
+            self.prg_bank = (val & FLAG_PRG_BANK) >> 4;
+            self.chr_bank = val & FLAG_CHR_BANK;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom_file::Mirroring;
+    use crate::ppu_bus::PpuBus;
+
+    #[test]
+    fn switches_the_prg_and_chr_banks() {
+        let prg_rom = (0..4).flat_map(|_| vec![0xff; 0x8000]).collect();
+        let chr_rom = (0..4).flat_map(|bank| vec![bank as u8; 0x2000]).collect();
+        let mut gxrom = Gxrom::new(Cartridge::from_memories(prg_rom, chr_rom, Mirroring::VERTICAL));
+
+        gxrom.cpu_write(0x8000, 0x21);
+        assert_eq!(gxrom.prg_bank, 2);
+        assert_eq!(gxrom.peek(0x0000), 1);
+    }
+
+    #[test]
+    fn bus_conflicts_and_the_rom_value() {
+        let mut prg_rom = vec![0xff; 0x10000];
+        prg_rom[0x0010] = 0x10;
+        let chr_rom = (0..4).flat_map(|bank| vec![bank as u8; 0x2000]).collect();
+        let mut gxrom = Gxrom::new(Cartridge::from_memories(prg_rom, chr_rom, Mirroring::VERTICAL));
+
+        gxrom.cpu_write(0x8010, 0x13);
+        assert_eq!(gxrom.prg_bank, 1);
+        assert_eq!(gxrom.peek(0x0000), 0);
+    }
+}
@@ -0,0 +1,301 @@
+use crate::mapper::{Mapper, Cartridge};
+use crate::rom_file::Mirroring;
+
+// https://www.nesdev.org/wiki/MMC1
+
+const FLAG_RESET: u8            = 0b10000000;
+const FLAG_MIRRORING: u8        = 0b00000011;
+const FLAG_PRG_MODE: u8         = 0b00001100;
+const FLAG_CHR_MODE: u8         = 0b00010000;
+const FLAG_PRG_BANK: u8         = 0b00001111;
+const FLAG_PRG_RAM_DISABLE: u8  = 0b00010000;
+const FLAG_PRG_OUTER_BANK: u8   = 0b00010000;
+
+/// Shift register value once reset, the marker bit reaches the bit 0 after 4 writes
+const SHIFT_REGISTER_RESET: u8 = 0b00010000;
+/// PRG ROM size selected by an outer bank on the SUROM/SXROM boards
+const PRG_OUTER_BANK_SIZE: usize = 0x40000;
+
+/// MMC1 (mapper 1, SxROM boards), its registers are loaded through a 5-bit serial port
+///
+/// The upper bits of the CHR bank 0 register are wired to the PRG ROM and PRG RAM on the boards with
+/// 8kB of CHR RAM: 256kB PRG ROM outer bank (SUROM, SXROM) and 8kB PRG RAM bank (SOROM, SXROM).
+pub struct Mmc1 {
+    cartridge: Cartridge,
+    shift_register: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+    /// CPU cycles since the last write to the serial port, counting the cycle of the write. A write
+    /// on the next cycle (the second write of the read-modify-write instructions) is ignored.
+    cycles_since_write: u8,
+}
+
+impl Mmc1 {
+    pub fn new(cartridge: Cartridge) -> Mmc1 {
+        let mut mmc1 = Mmc1 {
+            cartridge,
+            shift_register: SHIFT_REGISTER_RESET,
+            control: 0,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            cycles_since_write: u8::MAX,
+        };
+        // The last bank is fixed at $C000 on power-up, for the reset vector
+        mmc1.write_control(FLAG_PRG_MODE);
+        mmc1
+    }
+
+    /// Loads a bit in the shift register, the register selected by the address is written with the
+    /// 5th bit
+    fn write_serial(&mut self, address: u16, val: u8) {
+        if (val & FLAG_RESET) != 0 {
+            self.shift_register = SHIFT_REGISTER_RESET;
+            self.write_control(self.control | FLAG_PRG_MODE);
+            return;
+        }
+
+        let complete = (self.shift_register & 0x01) != 0;
+        self.shift_register = (self.shift_register >> 1) | ((val & 0x01) << 4);
+
+        if complete {
+            let data = self.shift_register;
+            match address {
+                0x8000..=0x9fff => self.write_control(data),
+                0xa000..=0xbfff => self.chr_bank_0 = data,
+                0xc000..=0xdfff => self.chr_bank_1 = data,
+                _ => self.prg_bank = data
+            }
+            self.shift_register = SHIFT_REGISTER_RESET;
+        }
+    }
+
+    fn write_control(&mut self, val: u8) {
+        self.control = val;
+        self.cartridge.nametables.set_mirroring(match val & FLAG_MIRRORING {
+            0 => Mirroring::SINGLE_SCREEN_A,
+            1 => Mirroring::SINGLE_SCREEN_B,
+            2 => Mirroring::VERTICAL,
+            _ => Mirroring::HORIZONTAL,
+        });
+    }
+
+    /// Gets the 16kB PRG ROM bank mapped at the given address
+    fn get_prg_bank(&self, address: u16) -> usize {
+        let outer_bank = if self.cartridge.prg_rom.len() > PRG_OUTER_BANK_SIZE {
+            (self.chr_bank_0 & FLAG_PRG_OUTER_BANK) as usize
+        } else {
+            0
+        };
+        let bank = (self.prg_bank & FLAG_PRG_BANK) as usize;
+        let upper = address >= 0xc000;
+
+        outer_bank | match (self.control & FLAG_PRG_MODE) >> 2 {
+            // 32kB mode, the low bit of the bank number is ignored
+            0 | 1 => (bank & !0x01) | upper as usize,
+            // First bank fixed at $8000
+            2 => if upper { bank } else { 0 },
+            // Last bank fixed at $C000
+            _ => if upper { 0x0f } else { bank },
+        }
+    }
+
+    /// Gets the 8kB PRG RAM bank, SOROM only uses the bit 3 of the CHR bank 0 register
+    fn get_prg_ram_bank(&self) -> usize {
+        match self.cartridge.prg_ram.len() {
+            0x4000 => ((self.chr_bank_0 >> 3) & 0x01) as usize,
+            _ => ((self.chr_bank_0 >> 2) & 0x03) as usize,
+        }
+    }
+
+    fn is_prg_ram_enabled(&self) -> bool {
+        (self.prg_bank & FLAG_PRG_RAM_DISABLE) == 0
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn get_cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    fn get_cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cartridge
+    }
+
+    fn cpu_peek(&self, address: u16) -> Option<u8> {
+        match address {
+            0x6000..=0x7fff if self.is_prg_ram_enabled() => {
+                self.cartridge.read_prg_ram(self.get_prg_ram_bank(), 0x2000, address)
+            },
+            0x8000..=0xffff => Some(self.cartridge.read_prg_rom(self.get_prg_bank(address), 0x4000, address)),
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, val: u8) {
+        match address {
+            0x6000..=0x7fff if self.is_prg_ram_enabled() => {
+                let bank = self.get_prg_ram_bank();
+                self.cartridge.write_prg_ram(bank, 0x2000, address, val);
+            },
+            0x8000..=0xffff => {
+                // The cycle of each write is ticked before its access
+                if self.cycles_since_write > 1 {
+                    self.write_serial(address, val);
+                }
+                self.cycles_since_write = 0;
+            },
+            _ => {}
+        }
+    }
+
+    fn cpu_tick(&mut self) {
+        self.cycles_since_write = self.cycles_since_write.saturating_add(1);
+    }
+
+    fn get_chr_bank(&self, address: u16) -> (usize, usize) {
+        if (self.control & FLAG_CHR_MODE) != 0 {
+            let bank = if (address & 0x1000) == 0 { self.chr_bank_0 } else { self.chr_bank_1 };
+            (bank as usize, 0x1000)
+        } else {
+            ((self.chr_bank_0 >> 1) as usize, 0x2000)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu_bus::PpuBus;
+
+    /// Builds an MMC1 with the given number of 16kB PRG ROM banks, each filled with its number, and
+    /// 4kB CHR ROM banks
+    fn new_mmc1(prg_banks: usize, chr_banks: usize, prg_ram_size: usize) -> Mmc1 {
+        let prg_rom = (0..prg_banks).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+        let chr_rom = (0..chr_banks).flat_map(|bank| vec![bank as u8; 0x1000]).collect();
+        let mut cartridge = Cartridge::from_memories(prg_rom, chr_rom, Mirroring::HORIZONTAL);
+        cartridge.prg_ram = vec![0; prg_ram_size];
+        Mmc1::new(cartridge)
+    }
+
+    /// Writes on a CPU cycle, the cartridge is ticked before the access as on the CPU bus
+    fn write_cycle(mmc1: &mut Mmc1, address: u16, val: u8) {
+        mmc1.cpu_tick();
+        mmc1.cpu_write(address, val);
+    }
+
+    /// Writes a register through the serial port, one CPU instruction per bit
+    fn write_register(mmc1: &mut Mmc1, address: u16, val: u8) {
+        for i in 0..5 {
+            mmc1.cpu_tick();
+            write_cycle(mmc1, address, (val >> i) & 0x01);
+        }
+    }
+
+    #[test]
+    fn last_bank_is_fixed_on_power_up() {
+        let mmc1 = new_mmc1(8, 0, 0x2000);
+        assert_eq!(mmc1.cpu_peek(0x8000), Some(0));
+        assert_eq!(mmc1.cpu_peek(0xc000), Some(7));
+    }
+
+    #[test]
+    fn prg_modes() {
+        let mut mmc1 = new_mmc1(8, 0, 0x2000);
+        write_register(&mut mmc1, 0xe000, 0x03);
+        assert_eq!(mmc1.cpu_peek(0x8000), Some(3));
+        assert_eq!(mmc1.cpu_peek(0xc000), Some(7));
+
+        write_register(&mut mmc1, 0x8000, 0b01000);
+        assert_eq!(mmc1.cpu_peek(0x8000), Some(0));
+        assert_eq!(mmc1.cpu_peek(0xc000), Some(3));
+
+        write_register(&mut mmc1, 0x8000, 0b00000);
+        assert_eq!(mmc1.cpu_peek(0x8000), Some(2));
+        assert_eq!(mmc1.cpu_peek(0xc000), Some(3));
+    }
+
+    #[test]
+    fn reset_bit_clears_the_shift_register() {
+        let mut mmc1 = new_mmc1(8, 0, 0x2000);
+        write_register(&mut mmc1, 0x8000, 0b00000);
+        write_cycle(&mut mmc1, 0xe000, 0x01);
+        mmc1.cpu_tick();
+        write_cycle(&mut mmc1, 0xe000, 0x80);
+
+        // The PRG mode is back to the fixed last bank
+        assert_eq!(mmc1.cpu_peek(0xc000), Some(7));
+        write_register(&mut mmc1, 0xe000, 0x02);
+        assert_eq!(mmc1.cpu_peek(0x8000), Some(2));
+    }
+
+    #[test]
+    fn consecutive_writes_are_ignored() {
+        let mut mmc1 = new_mmc1(8, 0, 0x2000);
+        for _ in 0..5 {
+            // The second write of a read-modify-write instruction is ignored
+            write_cycle(&mut mmc1, 0xe000, 0x01);
+            write_cycle(&mut mmc1, 0xe000, 0x00);
+            mmc1.cpu_tick();
+        }
+        assert_eq!(mmc1.cpu_peek(0x8000), Some(7));
+    }
+
+    #[test]
+    fn chr_modes() {
+        let mut mmc1 = new_mmc1(2, 8, 0x2000);
+        write_register(&mut mmc1, 0xa000, 0x03);
+        write_register(&mut mmc1, 0xc000, 0x05);
+        assert_eq!(mmc1.peek(0x0000), 2);
+        assert_eq!(mmc1.peek(0x1000), 3);
+
+        write_register(&mut mmc1, 0x8000, 0b11100);
+        assert_eq!(mmc1.peek(0x0000), 3);
+        assert_eq!(mmc1.peek(0x1000), 5);
+    }
+
+    #[test]
+    fn mirroring_is_controlled_at_runtime() {
+        let mut mmc1 = new_mmc1(2, 0, 0x2000);
+        write_register(&mut mmc1, 0x8000, 0b01110);
+        assert_eq!(mmc1.get_mirroring(), Mirroring::VERTICAL);
+        write_register(&mut mmc1, 0x8000, 0b01101);
+        assert_eq!(mmc1.get_mirroring(), Mirroring::SINGLE_SCREEN_B);
+    }
+
+    #[test]
+    fn prg_ram_can_be_disabled() {
+        let mut mmc1 = new_mmc1(2, 0, 0x2000);
+        mmc1.cpu_write(0x6000, 0x42);
+        assert_eq!(mmc1.cpu_peek(0x6000), Some(0x42));
+
+        write_register(&mut mmc1, 0xe000, FLAG_PRG_RAM_DISABLE);
+        assert_eq!(mmc1.cpu_peek(0x6000), None);
+    }
+
+    #[test]
+    fn surom_selects_the_outer_prg_bank() {
+        let mut mmc1 = new_mmc1(32, 0, 0x2000);
+        assert_eq!(mmc1.cpu_peek(0xc000), Some(15));
+
+        write_register(&mut mmc1, 0xa000, FLAG_PRG_OUTER_BANK);
+        write_register(&mut mmc1, 0xe000, 0x02);
+        assert_eq!(mmc1.cpu_peek(0x8000), Some(18));
+        assert_eq!(mmc1.cpu_peek(0xc000), Some(31));
+    }
+
+    #[test]
+    fn sxrom_selects_the_prg_ram_bank() {
+        let mut mmc1 = new_mmc1(32, 0, 0x8000);
+        for bank in 0..4 {
+            write_register(&mut mmc1, 0xa000, bank << 2);
+            mmc1.cpu_write(0x6000, bank);
+        }
+        for bank in 0..4 {
+            write_register(&mut mmc1, 0xa000, bank << 2);
+            assert_eq!(mmc1.cpu_peek(0x6000), Some(bank));
+        }
+    }
+}
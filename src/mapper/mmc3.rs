@@ -0,0 +1,355 @@
+use crate::mapper::{Mapper, Cartridge};
+use crate::rom_file::Mirroring;
+
+// https://www.nesdev.org/wiki/MMC3
+
+const FLAG_BANK_REGISTER: u8    = 0b00000111;
+const FLAG_PRG_MODE: u8         = 0b01000000;
+const FLAG_CHR_INVERSION: u8    = 0b10000000;
+const FLAG_MIRRORING: u8        = 0b00000001;
+const FLAG_PRG_RAM_ENABLE: u8   = 0b10000000;
+const FLAG_PRG_RAM_PROTECT: u8  = 0b01000000;
+
+const PPU_A12: u16 = 0x1000;
+/// Number of CPU cycles (M2 falling edges) A12 has to stay low before a rising edge clocks the IRQ
+/// counter, it filters the A12 toggles of the sprite fetches
+const A12_FILTER_CYCLES: u8 = 3;
+
+/// Revision of the MMC3, they differ in the way the IRQ counter raises the IRQ when it is 0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mmc3Revision {
+    /// "New" behaviour (MMC3B and MMC3C by Sharp): the IRQ is raised at each clock leaving the
+    /// counter at 0, so a latch of 0 raises an IRQ on every scanline
+    SHARP,
+    /// "Old" behaviour (MMC3A by NEC): the IRQ is only raised when the counter is decremented to 0, or
+    /// reloaded with 0 after a write to $C001
+    NEC,
+}
+
+/// MMC3 (mapper 4, TxROM boards): 8kB PRG ROM banks, 1kB/2kB CHR banks and a scanline counter
+/// clocked by the rising edges of the PPU A12 line
+pub struct Mmc3 {
+    cartridge: Cartridge,
+    revision: Mmc3Revision,
+    bank_select: u8,
+    /// R0-R5 (CHR banks) and R6-R7 (PRG banks)
+    bank_registers: [u8; 8],
+    prg_ram_protect: u8,
+    /// The four-screen boards have their own nametable memory and ignore the mirroring register
+    four_screen: bool,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    /// Level of A12 on the last PPU access
+    a12: bool,
+    /// CPU cycles since A12 went low
+    a12_low_cycles: u8,
+}
+
+impl Mmc3 {
+    pub fn new(cartridge: Cartridge, revision: Mmc3Revision) -> Mmc3 {
+        let four_screen = cartridge.nametables.get_mirroring() == Mirroring::FOUR_SCREEN;
+
+        Mmc3 {
+            cartridge,
+            revision,
+            bank_select: 0,
+            bank_registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            // Some games never enable the PRG RAM
+            prg_ram_protect: FLAG_PRG_RAM_ENABLE,
+            four_screen,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            a12: false,
+            a12_low_cycles: 0,
+        }
+    }
+
+    /// Gets the 8kB PRG ROM bank mapped at the given address, the PRG mode swaps $8000 and $C000
+    fn get_prg_bank(&self, address: u16) -> usize {
+        let second_last = self.cartridge.get_prg_rom_banks(0x2000).saturating_sub(2);
+        let prg_mode = (self.bank_select & FLAG_PRG_MODE) != 0;
+
+        match (address >> 13) & 0x03 {
+            0 if prg_mode => second_last,
+            0 => (self.bank_registers[6] & 0x3f) as usize,
+            1 => (self.bank_registers[7] & 0x3f) as usize,
+            2 if prg_mode => (self.bank_registers[6] & 0x3f) as usize,
+            2 => second_last,
+            _ => second_last + 1,
+        }
+    }
+
+    fn is_prg_ram_enabled(&self) -> bool {
+        (self.prg_ram_protect & FLAG_PRG_RAM_ENABLE) != 0
+    }
+
+    fn is_prg_ram_writable(&self) -> bool {
+        self.is_prg_ram_enabled() && (self.prg_ram_protect & FLAG_PRG_RAM_PROTECT) == 0
+    }
+
+    fn write_mirroring(&mut self, val: u8) {
+        if !self.four_screen {
+            self.cartridge.nametables.set_mirroring(if (val & FLAG_MIRRORING) != 0 {
+                Mirroring::HORIZONTAL
+            } else {
+                Mirroring::VERTICAL
+            });
+        }
+    }
+
+    /// Follows the A12 line of the PPU address bus, a rising edge after a long enough low level
+    /// clocks the IRQ counter
+    fn update_a12(&mut self, address: u16) {
+        let a12 = (address & PPU_A12) != 0;
+        if a12 && !self.a12 && self.a12_low_cycles >= A12_FILTER_CYCLES {
+            self.clock_irq_counter();
+        }
+        if a12 != self.a12 {
+            self.a12 = a12;
+            self.a12_low_cycles = 0;
+        }
+    }
+
+    fn clock_irq_counter(&mut self) {
+        let previous_counter = self.irq_counter;
+
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        let irq = match self.revision {
+            Mmc3Revision::SHARP => self.irq_counter == 0,
+            Mmc3Revision::NEC => self.irq_counter == 0 && (previous_counter != 0 || self.irq_reload),
+        };
+        if irq && self.irq_enabled {
+            self.irq_pending = true;
+        }
+        self.irq_reload = false;
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn get_cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    fn get_cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cartridge
+    }
+
+    fn cpu_peek(&self, address: u16) -> Option<u8> {
+        match address {
+            0x6000..=0x7fff if self.is_prg_ram_enabled() => self.cartridge.read_prg_ram(0, 0x2000, address),
+            0x8000..=0xffff => Some(self.cartridge.read_prg_rom(self.get_prg_bank(address), 0x2000, address)),
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, val: u8) {
+        let even = (address & 0x01) == 0;
+
+        match address {
+            0x6000..=0x7fff if self.is_prg_ram_writable() => self.cartridge.write_prg_ram(0, 0x2000, address, val),
+            0x8000..=0x9fff if even => self.bank_select = val,
+            0x8000..=0x9fff => self.bank_registers[(self.bank_select & FLAG_BANK_REGISTER) as usize] = val,
+            0xa000..=0xbfff if even => self.write_mirroring(val),
+            0xa000..=0xbfff => self.prg_ram_protect = val,
+            0xc000..=0xdfff if even => self.irq_latch = val,
+            0xc000..=0xdfff => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            },
+            0xe000..=0xffff if even => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            },
+            0xe000..=0xffff => self.irq_enabled = true,
+            _ => {}
+        }
+    }
+
+    fn cpu_tick(&mut self) {
+        if !self.a12 {
+            self.a12_low_cycles = self.a12_low_cycles.saturating_add(1);
+        }
+    }
+
+    fn irq_line(&self) -> bool {
+        self.irq_pending
+    }
+
+    /// The CHR is mapped in 1kB banks, the CHR inversion swaps $0000-$0FFF and $1000-$1FFF
+    fn get_chr_bank(&self, address: u16) -> (usize, usize) {
+        let address = if (self.bank_select & FLAG_CHR_INVERSION) != 0 { address ^ PPU_A12 } else { address };
+        let slot = ((address >> 10) & 0x07) as usize;
+
+        let bank = match slot {
+            // 2kB banks, the low bit of the bank number is ignored
+            0..=3 => ((self.bank_registers[slot >> 1] & 0xfe) as usize) | (slot & 0x01),
+            _ => self.bank_registers[slot - 2] as usize,
+        };
+        (bank, 0x0400)
+    }
+
+    fn update_ppu_address(&mut self, address: u16) {
+        self.update_a12(address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu_bus::PpuBus;
+    use crate::ppu::Ppu;
+    use crate::memory::{PPU_CTRL, PPU_MASK, FLAG_SPRITE_TILE_SELECT, FLAG_SHOW_BACKGROUND, FLAG_SHOW_SPRITES};
+
+    /// Builds an MMC3 with 16 PRG ROM banks and 32 CHR ROM banks of 8kB, each filled with its number
+    fn new_mmc3(revision: Mmc3Revision) -> Mmc3 {
+        let prg_rom = (0..16).flat_map(|bank| vec![bank as u8; 0x2000]).collect();
+        let chr_rom = (0..256).flat_map(|bank| vec![bank as u8; 0x0400]).collect();
+        Mmc3::new(Cartridge::from_memories(prg_rom, chr_rom, Mirroring::VERTICAL), revision)
+    }
+
+    /// Simulates a scanline of rendering with the background at $0000 and the sprites at $1000
+    fn clock_scanline(mmc3: &mut Mmc3) {
+        mmc3.set_address(0x0000);
+        for _ in 0..A12_FILTER_CYCLES {
+            mmc3.cpu_tick();
+        }
+        mmc3.set_address(0x1000);
+    }
+
+    #[test]
+    fn prg_modes() {
+        let mut mmc3 = new_mmc3(Mmc3Revision::SHARP);
+        mmc3.cpu_write(0x8000, 6);
+        mmc3.cpu_write(0x8001, 3);
+        mmc3.cpu_write(0x8000, 7);
+        mmc3.cpu_write(0x8001, 4);
+        assert_eq!(mmc3.cpu_peek(0x8000), Some(3));
+        assert_eq!(mmc3.cpu_peek(0xa000), Some(4));
+        assert_eq!(mmc3.cpu_peek(0xc000), Some(14));
+        assert_eq!(mmc3.cpu_peek(0xe000), Some(15));
+
+        mmc3.cpu_write(0x8000, FLAG_PRG_MODE);
+        assert_eq!(mmc3.cpu_peek(0x8000), Some(14));
+        assert_eq!(mmc3.cpu_peek(0xa000), Some(4));
+        assert_eq!(mmc3.cpu_peek(0xc000), Some(3));
+        assert_eq!(mmc3.cpu_peek(0xe000), Some(15));
+    }
+
+    #[test]
+    fn chr_inversion() {
+        let mut mmc3 = new_mmc3(Mmc3Revision::SHARP);
+        for (register, bank) in [9, 20, 30, 31, 32, 33].iter().enumerate() {
+            mmc3.cpu_write(0x8000, register as u8);
+            mmc3.cpu_write(0x8001, *bank);
+        }
+        // The 2kB banks ignore the low bit
+        assert_eq!(mmc3.peek(0x0000), 8);
+        assert_eq!(mmc3.peek(0x0400), 9);
+        assert_eq!(mmc3.peek(0x0800), 20);
+        assert_eq!(mmc3.peek(0x1000), 30);
+        assert_eq!(mmc3.peek(0x1c00), 33);
+
+        mmc3.cpu_write(0x8000, FLAG_CHR_INVERSION);
+        assert_eq!(mmc3.peek(0x0000), 30);
+        assert_eq!(mmc3.peek(0x1000), 8);
+        assert_eq!(mmc3.peek(0x1c00), 21);
+    }
+
+    #[test]
+    fn mirroring_and_prg_ram_protect() {
+        let mut mmc3 = new_mmc3(Mmc3Revision::SHARP);
+        mmc3.cpu_write(0xa000, 0x01);
+        assert_eq!(mmc3.get_mirroring(), Mirroring::HORIZONTAL);
+
+        mmc3.cpu_write(0x6000, 0x42);
+        mmc3.cpu_write(0xa001, FLAG_PRG_RAM_ENABLE | FLAG_PRG_RAM_PROTECT);
+        mmc3.cpu_write(0x6000, 0x24);
+        assert_eq!(mmc3.cpu_peek(0x6000), Some(0x42));
+
+        mmc3.cpu_write(0xa001, 0x00);
+        assert_eq!(mmc3.cpu_peek(0x6000), None);
+    }
+
+    #[test]
+    fn irq_counter_reload_and_acknowledge() {
+        let mut mmc3 = new_mmc3(Mmc3Revision::SHARP);
+        mmc3.cpu_write(0xc000, 2);
+        mmc3.cpu_write(0xc001, 0);
+        mmc3.cpu_write(0xe001, 0);
+
+        clock_scanline(&mut mmc3);
+        clock_scanline(&mut mmc3);
+        assert!(!mmc3.irq_line());
+        clock_scanline(&mut mmc3);
+        assert!(mmc3.irq_line());
+
+        mmc3.cpu_write(0xe000, 0);
+        assert!(!mmc3.irq_line());
+        // The counter is reloaded from the latch after it reached 0
+        clock_scanline(&mut mmc3);
+        assert!(!mmc3.irq_line());
+    }
+
+    #[test]
+    fn a12_toggles_are_filtered() {
+        let mut mmc3 = new_mmc3(Mmc3Revision::SHARP);
+        mmc3.cpu_write(0xc000, 0);
+        mmc3.cpu_write(0xe001, 0);
+
+        mmc3.set_address(0x0000);
+        mmc3.cpu_tick();
+        mmc3.set_address(0x1000);
+        assert!(!mmc3.irq_line());
+    }
+
+    #[test]
+    fn revisions_with_a_latch_of_0() {
+        for &(revision, irq) in [(Mmc3Revision::SHARP, true), (Mmc3Revision::NEC, false)].iter() {
+            let mut mmc3 = new_mmc3(revision);
+            mmc3.cpu_write(0xc000, 0);
+            mmc3.cpu_write(0xe001, 0);
+
+            // The counter is already 0, it is reloaded with 0
+            clock_scanline(&mut mmc3);
+            assert_eq!(mmc3.irq_line(), irq);
+
+            // Both revisions raise the IRQ after a write to $C001
+            mmc3.cpu_write(0xc001, 0);
+            clock_scanline(&mut mmc3);
+            assert!(mmc3.irq_line());
+        }
+    }
+
+    #[test]
+    fn irq_counter_is_clocked_once_per_rendered_scanline() {
+        let mut ppu = Ppu::new();
+        let mut mmc3 = new_mmc3(Mmc3Revision::SHARP);
+        ppu.write_register(PPU_CTRL, FLAG_SPRITE_TILE_SELECT, &mut mmc3);
+        ppu.write_register(PPU_MASK, FLAG_SHOW_BACKGROUND | FLAG_SHOW_SPRITES, &mut mmc3);
+        mmc3.cpu_write(0xc000, 3);
+        mmc3.cpu_write(0xe001, 0);
+
+        // The first clock (scanline 0) reloads the counter, then it reaches 0 on the scanline 3
+        let mut dots = 0;
+        while !mmc3.irq_line() {
+            for _ in 0..3 {
+                ppu.step(&mut mmc3);
+                dots += 1;
+            }
+            mmc3.cpu_tick();
+        }
+        assert_eq!(dots / 341, 3);
+    }
+}
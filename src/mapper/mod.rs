@@ -0,0 +1,365 @@
+use std::io;
+#[cfg(test)]
+use std::cell::{Cell, RefCell};
+#[cfg(test)]
+use std::rc::Rc;
+
+use crate::ppu_bus::{PpuBus, Nametables};
+use crate::rom_file::{RomFile, Mirroring};
+
+pub mod nrom;
+pub mod mmc1;
+pub mod mmc3;
+pub mod uxrom;
+pub mod cnrom;
+pub mod axrom;
+pub mod color_dreams;
+pub mod gxrom;
+
+// https://www.nesdev.org/wiki/Mapper
+// https://www.nesdev.org/wiki/Cartridge_connector
+
+/// Size of the CHR RAM of the cartridges without CHR ROM
+pub const CHR_RAM_SIZE: usize = 0x2000;
+/// Size of the PRG RAM mapped at $6000-$7FFF
+pub const PRG_RAM_SIZE: usize = 0x2000;
+
+/// Cartridge board, the mapper hardware switches the banks of the memories of the cartridge seen by
+/// the CPU ($4020-$FFFF) and by the PPU ($0000-$3EFF, through the PPU bus)
+pub trait Mapper {
+    /// Gets the memories of the cartridge
+    fn get_cartridge(&self) -> &Cartridge;
+
+    fn get_cartridge_mut(&mut self) -> &mut Cartridge;
+
+    /// Reads the data at the given CPU address, with the side effects of a CPU read. None is returned
+    /// when the cartridge does not drive the data bus (open bus).
+    fn cpu_read(&mut self, address: u16) -> Option<u8> {
+        self.cpu_peek(address)
+    }
+
+    /// Reads the data at the given CPU address without any side effect, for debugging purposes
+    fn cpu_peek(&self, address: u16) -> Option<u8>;
+
+    /// Writes the data at the given CPU address, the mapper registers are usually written there
+    fn cpu_write(&mut self, address: u16, val: u8);
+
+    /// Advances the mapper by one CPU cycle (M2)
+    fn cpu_tick(&mut self) {}
+
+    /// Gets the level of the IRQ output
+    fn irq_line(&self) -> bool {
+        false
+    }
+
+    /// Gets the CHR bank mapped at the given PPU address ($0000-$1FFF) and the size of the bank, a
+    /// single 8kB bank by default
+    fn get_chr_bank(&self, _address: u16) -> (usize, usize) {
+        (0, 0x2000)
+    }
+
+    /// Called with every address driven on the PPU bus: the reads, the writes and the VRAM address
+    /// set by PPUADDR, for the mappers following the address lines
+    fn update_ppu_address(&mut self, _address: u16) {}
+
+    /// Called at the start of each PPU scanline (the pre-render line is the last one), for the
+    /// mappers counting the scanlines without following the address lines
+    fn notify_ppu_scanline(&mut self, _scanline: u32) {}
+
+    /// Advances the mapper by one PPU dot
+    fn ppu_tick(&mut self) {}
+
+    /// Gets the current nametable mirroring
+    fn get_mirroring(&self) -> Mirroring {
+        self.get_cartridge().nametables.get_mirroring()
+    }
+}
+
+/// The PPU bus of a cartridge: the CHR banks selected by the mapper ($0000-$1FFF) and the nametables
+impl<M: Mapper + ?Sized> PpuBus for M {
+    fn read(&mut self, address: u16) -> u8 {
+        self.update_ppu_address(address);
+        self.peek(address)
+    }
+
+    fn write(&mut self, address: u16, val: u8) {
+        self.update_ppu_address(address);
+        match address & 0x3fff {
+            0x0000..=0x1fff => {
+                let (bank, bank_size) = self.get_chr_bank(address);
+                self.get_cartridge_mut().write_chr(bank, bank_size, address, val);
+            },
+            _ => self.get_cartridge_mut().nametables.write(address, val)
+        }
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        match address & 0x3fff {
+            0x0000..=0x1fff => {
+                let (bank, bank_size) = self.get_chr_bank(address);
+                self.get_cartridge().read_chr(bank, bank_size, address)
+            },
+            _ => self.get_cartridge().nametables.read(address)
+        }
+    }
+
+    fn set_address(&mut self, address: u16) {
+        self.update_ppu_address(address);
+    }
+
+    fn notify_scanline(&mut self, scanline: u32) {
+        self.notify_ppu_scanline(scanline);
+    }
+
+    fn tick(&mut self) {
+        self.ppu_tick();
+    }
+}
+
+impl PpuBus for Box<dyn Mapper> {
+    fn read(&mut self, address: u16) -> u8 {
+        (**self).read(address)
+    }
+
+    fn write(&mut self, address: u16, val: u8) {
+        (**self).write(address, val)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        (**self).peek(address)
+    }
+
+    fn set_address(&mut self, address: u16) {
+        (**self).set_address(address)
+    }
+
+    fn notify_scanline(&mut self, scanline: u32) {
+        (**self).notify_scanline(scanline)
+    }
+
+    fn tick(&mut self) {
+        (**self).tick()
+    }
+}
+
+/// Builds the mapper of the given ROM file, from its mapper number
+pub fn new_mapper(rom_file: &RomFile) -> io::Result<Box<dyn Mapper>> {
+    let cartridge = Cartridge::new(rom_file);
+    // The mappers fix their last PRG ROM banks, there must be at least one 16kB bank
+    if cartridge.prg_rom.len() < 0x4000 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid PRG ROM size: {} bytes", cartridge.prg_rom.len())));
+    }
+
+    match rom_file.get_mapper_type() {
+        0 => Ok(Box::new(nrom::Nrom::new(cartridge, rom_file.has_prg_ram()))),
+        1 => Ok(Box::new(mmc1::Mmc1::new(cartridge))),
+        // The submapper 1 boards have no bus conflicts, the submapper 2 boards have some
+        2 => Ok(Box::new(uxrom::Uxrom::new(cartridge, rom_file.get_submapper() != 1))),
+        3 => Ok(Box::new(cnrom::Cnrom::new(cartridge, rom_file.get_submapper() != 1))),
+        4 => {
+            // The submapper 4 boards have an MMC3A
+            let revision = if rom_file.get_submapper() == 4 { mmc3::Mmc3Revision::NEC } else { mmc3::Mmc3Revision::SHARP };
+            Ok(Box::new(mmc3::Mmc3::new(cartridge, revision)))
+        },
+        7 => Ok(Box::new(axrom::Axrom::new(cartridge, rom_file.get_submapper() == 2))),
+        11 => Ok(Box::new(color_dreams::ColorDreams::new(cartridge))),
+        66 => Ok(Box::new(gxrom::Gxrom::new(cartridge))),
+        mapper_type => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Unsupported mapper: {}", mapper_type)))
+    }
+}
+
+/// NROM board recording the PPU dots, the addresses and the scanlines it is notified of, to check
+/// the timing seen by the mappers
+#[cfg(test)]
+pub struct ProbeMapper {
+    cartridge: Cartridge,
+    /// Number of PPU dots ticked
+    pub ppu_dots: Rc<Cell<u64>>,
+    /// Scanlines notified, in order
+    pub scanlines: Rc<RefCell<Vec<u32>>>,
+    /// PPU addresses driven, with the dot of the access counted from the power-up (the first dot is 1)
+    pub addresses: Rc<RefCell<Vec<(u64, u16)>>>,
+}
+
+#[cfg(test)]
+impl ProbeMapper {
+    pub fn new(cartridge: Cartridge) -> ProbeMapper {
+        ProbeMapper {
+            cartridge,
+            ppu_dots: Rc::new(Cell::new(0)),
+            scanlines: Rc::new(RefCell::new(Vec::new())),
+            addresses: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Mapper for ProbeMapper {
+    fn get_cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    fn get_cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cartridge
+    }
+
+    fn cpu_peek(&self, address: u16) -> Option<u8> {
+        match address {
+            0x8000..=0xffff => Some(self.cartridge.read_prg_rom(0, 0x8000, address)),
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, _address: u16, _val: u8) {}
+
+    fn update_ppu_address(&mut self, address: u16) {
+        // The dot is ticked once its accesses are done
+        self.addresses.borrow_mut().push((self.ppu_dots.get() + 1, address));
+    }
+
+    fn notify_ppu_scanline(&mut self, scanline: u32) {
+        self.scanlines.borrow_mut().push(scanline);
+    }
+
+    fn ppu_tick(&mut self) {
+        self.ppu_dots.set(self.ppu_dots.get() + 1);
+    }
+}
+
+/// Memories of a cartridge, their banks are selected by the mapper
+pub struct Cartridge {
+    pub prg_rom: Vec<u8>,
+    pub prg_ram: Vec<u8>,
+    /// CHR ROM, or CHR RAM when the ROM file has none
+    pub chr: Vec<u8>,
+    pub chr_ram: bool,
+    /// Nametables, through the CIRAM of the console (and the VRAM of the cartridge for the
+    /// four-screen boards)
+    pub nametables: Nametables,
+}
+
+impl Cartridge {
+    pub fn new(rom_file: &RomFile) -> Cartridge {
+        let mut cartridge = Cartridge::from_memories(
+            rom_file.prg_data(), rom_file.chr_data(), rom_file.get_mirroring());
+        cartridge.prg_ram = vec![0; rom_file.prg_ram_size()];
+        cartridge
+    }
+
+    /// Builds a cartridge from its ROM data, with CHR RAM when there is no CHR ROM
+    pub fn from_memories(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> Cartridge {
+        let chr_ram = chr_rom.is_empty();
+
+        Cartridge {
+            prg_rom,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            chr: if chr_ram { vec![0; CHR_RAM_SIZE] } else { chr_rom },
+            chr_ram,
+            nametables: Nametables::new(mirroring),
+        }
+    }
+
+    /// Reads the PRG ROM through a bank of the given size, the bank number wraps around the ROM size
+    pub fn read_prg_rom(&self, bank: usize, bank_size: usize, address: u16) -> u8 {
+        let offset = (address as usize) & (bank_size - 1);
+        self.prg_rom[(bank * bank_size + offset) % self.prg_rom.len()]
+    }
+
+    /// Gets the number of PRG ROM banks of the given size
+    pub fn get_prg_rom_banks(&self, bank_size: usize) -> usize {
+        (self.prg_rom.len() / bank_size).max(1)
+    }
+
+    /// Reads the PRG RAM through a bank of the given size, None is returned when there is no PRG RAM.
+    /// The bank number wraps around the RAM size, and a RAM smaller than the bank is mirrored.
+    pub fn read_prg_ram(&self, bank: usize, bank_size: usize, address: u16) -> Option<u8> {
+        self.get_prg_ram_index(bank, bank_size, address).map(|index| self.prg_ram[index])
+    }
+
+    /// Writes the PRG RAM through a bank of the given size
+    pub fn write_prg_ram(&mut self, bank: usize, bank_size: usize, address: u16, val: u8) {
+        if let Some(index) = self.get_prg_ram_index(bank, bank_size, address) {
+            self.prg_ram[index] = val;
+        }
+    }
+
+    fn get_prg_ram_index(&self, bank: usize, bank_size: usize, address: u16) -> Option<usize> {
+        if self.prg_ram.is_empty() {
+            return None;
+        }
+
+        let offset = (address as usize) & (bank_size - 1);
+        Some((bank * bank_size + offset) % self.prg_ram.len())
+    }
+
+    /// Reads the CHR memory through a bank of the given size, the bank number wraps around the
+    /// memory size
+    pub fn read_chr(&self, bank: usize, bank_size: usize, address: u16) -> u8 {
+        self.chr[self.get_chr_index(bank, bank_size, address)]
+    }
+
+    /// Writes the CHR memory through a bank of the given size, the CHR ROM is not written
+    pub fn write_chr(&mut self, bank: usize, bank_size: usize, address: u16, val: u8) {
+        if self.chr_ram {
+            let index = self.get_chr_index(bank, bank_size, address);
+            self.chr[index] = val;
+        }
+    }
+
+    fn get_chr_index(&self, bank: usize, bank_size: usize, address: u16) -> usize {
+        let offset = (address as usize) & (bank_size - 1);
+        (bank * bank_size + offset) % self.chr.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chr_rom_is_read_only() {
+        let mut cartridge = Cartridge::from_memories(vec![0; 0x4000], Vec::new(), Mirroring::HORIZONTAL);
+        cartridge.write_chr(0, 0x2000, 0x0010, 0x42);
+        assert_eq!(cartridge.read_chr(0, 0x2000, 0x0010), 0x42);
+
+        let mut cartridge = Cartridge::from_memories(vec![0; 0x4000], vec![0x24; 0x2000], Mirroring::HORIZONTAL);
+        cartridge.write_chr(0, 0x2000, 0x0010, 0x42);
+        assert_eq!(cartridge.read_chr(0, 0x2000, 0x0010), 0x24);
+    }
+
+    #[test]
+    fn rom_without_prg_rom_is_rejected() {
+        // Header of an MMC3 ROM file with no PRG ROM and 8kB of CHR ROM
+        let mut data = vec![0x4e, 0x45, 0x53, 0x1a, 0x00, 0x01, 0x40, 0x00];
+        data.resize(16 + 0x2000, 0);
+        let rom_file = RomFile { file_path: String::new(), data };
+
+        let err = new_mapper(&rom_file).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn ppu_bus_notifications_are_forwarded_to_the_mapper() {
+        let probe = ProbeMapper::new(Cartridge::from_memories(vec![0; 0x8000], Vec::new(), Mirroring::HORIZONTAL));
+        let (ppu_dots, scanlines) = (probe.ppu_dots.clone(), probe.scanlines.clone());
+        let mut mapper: Box<dyn Mapper> = Box::new(probe);
+
+        mapper.tick();
+        mapper.tick();
+        mapper.notify_scanline(12);
+        assert_eq!(ppu_dots.get(), 2);
+        assert_eq!(*scanlines.borrow(), vec![12]);
+    }
+
+    #[test]
+    fn banks_wrap_around_the_memory_size() {
+        let prg_rom: Vec<u8> = (0..4).flat_map(|bank| vec![bank as u8; 0x2000]).collect();
+        let cartridge = Cartridge::from_memories(prg_rom, Vec::new(), Mirroring::HORIZONTAL);
+        assert_eq!(cartridge.read_prg_rom(1, 0x2000, 0x8000), 1);
+        assert_eq!(cartridge.read_prg_rom(6, 0x2000, 0xa000), 2);
+    }
+}
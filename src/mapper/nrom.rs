@@ -0,0 +1,91 @@
+use crate::mapper::{Mapper, Cartridge};
+
+// https://www.nesdev.org/wiki/NROM
+
+/// NROM (mapper 0), no bank switching: 16kB (NROM-128, mirrored at $C000) or 32kB (NROM-256) of PRG
+/// ROM and 8kB of CHR ROM or RAM
+pub struct Nrom {
+    cartridge: Cartridge,
+    /// PRG RAM at $6000-$7FFF, only found on the Family BASIC board
+    prg_ram: bool,
+}
+
+impl Nrom {
+    pub fn new(cartridge: Cartridge, prg_ram: bool) -> Nrom {
+        Nrom { cartridge, prg_ram }
+    }
+}
+
+impl Mapper for Nrom {
+    fn get_cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    fn get_cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cartridge
+    }
+
+    fn cpu_peek(&self, address: u16) -> Option<u8> {
+        match address {
+            0x6000..=0x7fff if self.prg_ram => self.cartridge.read_prg_ram(0, 0x2000, address),
+            // The 16kB of the NROM-128 are mirrored by the wrap around of the ROM size
+            0x8000..=0xffff => Some(self.cartridge.read_prg_rom(0, 0x8000, address)),
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, val: u8) {
+        match address {
+            0x6000..=0x7fff if self.prg_ram => self.cartridge.write_prg_ram(0, 0x2000, address, val),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom_file::Mirroring;
+    use crate::ppu_bus::PpuBus;
+
+    fn new_nrom(prg_banks: usize, chr_rom: Vec<u8>, prg_ram: bool) -> Nrom {
+        let prg_rom = (0..prg_banks).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+        Nrom::new(Cartridge::from_memories(prg_rom, chr_rom, Mirroring::VERTICAL), prg_ram)
+    }
+
+    #[test]
+    fn nrom_128_is_mirrored() {
+        let nrom = new_nrom(1, Vec::new(), false);
+        assert_eq!(nrom.cpu_peek(0x8000), Some(0));
+        assert_eq!(nrom.cpu_peek(0xc000), Some(0));
+    }
+
+    #[test]
+    fn nrom_256_is_linear() {
+        let nrom = new_nrom(2, Vec::new(), false);
+        assert_eq!(nrom.cpu_peek(0xbfff), Some(0));
+        assert_eq!(nrom.cpu_peek(0xc000), Some(1));
+    }
+
+    #[test]
+    fn chr_ram_is_allocated_without_chr_rom() {
+        let mut nrom = new_nrom(1, Vec::new(), false);
+        nrom.write(0x1fff, 0x42);
+        assert_eq!(nrom.read(0x1fff), 0x42);
+
+        let mut nrom = new_nrom(1, vec![0x24; 0x2000], false);
+        nrom.write(0x1fff, 0x42);
+        assert_eq!(nrom.read(0x1fff), 0x24);
+    }
+
+    #[test]
+    fn prg_ram_is_optional() {
+        let mut nrom = new_nrom(1, Vec::new(), false);
+        nrom.cpu_write(0x6000, 0x42);
+        assert_eq!(nrom.cpu_peek(0x6000), None);
+
+        let mut nrom = new_nrom(1, Vec::new(), true);
+        nrom.cpu_write(0x6000, 0x42);
+        assert_eq!(nrom.cpu_peek(0x6000), Some(0x42));
+    }
+}
@@ -0,0 +1,88 @@
+use crate::mapper::{Mapper, Cartridge};
+
+// https://www.nesdev.org/wiki/UxROM
+
+/// UxROM (mapper 2), switchable 16kB PRG ROM bank at $8000 and last bank fixed at $C000, 8kB of CHR
+/// RAM
+pub struct Uxrom {
+    cartridge: Cartridge,
+    prg_bank: u8,
+    /// The ROM drives the data bus during the writes to the bank register (UNROM, UOROM)
+    bus_conflicts: bool,
+}
+
+impl Uxrom {
+    pub fn new(cartridge: Cartridge, bus_conflicts: bool) -> Uxrom {
+        Uxrom {
+            cartridge,
+            prg_bank: 0,
+            bus_conflicts,
+        }
+    }
+
+    fn read_prg_rom(&self, address: u16) -> u8 {
+        let bank = if address < 0xc000 {
+            self.prg_bank as usize
+        } else {
+            self.cartridge.get_prg_rom_banks(0x4000) - 1
+        };
+        self.cartridge.read_prg_rom(bank, 0x4000, address)
+    }
+}
+
+impl Mapper for Uxrom {
+    fn get_cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    fn get_cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cartridge
+    }
+
+    fn cpu_peek(&self, address: u16) -> Option<u8> {
+        match address {
+            0x8000..=0xffff => Some(self.read_prg_rom(address)),
+            _ => None
+        }
+    }
+
+    fn cpu_write(&mut self, address: u16, val: u8) {
+        if address >= 0x8000 {
+            self.prg_bank = if self.bus_conflicts { val & self.read_prg_rom(address) } else { val };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom_file::Mirroring;
+
+    fn new_uxrom(bus_conflicts: bool) -> Uxrom {
+        let mut prg_rom: Vec<u8> = (0..8).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+        prg_rom[0x4000 * 7 + 0x0010] = 0xff;
+        Uxrom::new(Cartridge::from_memories(prg_rom, Vec::new(), Mirroring::VERTICAL), bus_conflicts)
+    }
+
+    #[test]
+    fn switches_the_first_bank() {
+        let mut uxrom = new_uxrom(false);
+        uxrom.cpu_write(0x8000, 0x03);
+        assert_eq!(uxrom.cpu_peek(0x8000), Some(3));
+        assert_eq!(uxrom.cpu_peek(0xc000), Some(7));
+    }
+
+    #[test]
+    fn bus_conflicts_and_the_rom_value() {
+        let mut uxrom = new_uxrom(true);
+        // The last bank is filled with 7, except at $C010
+        uxrom.cpu_write(0xc000, 0x05);
+        assert_eq!(uxrom.cpu_peek(0x8000), Some(5));
+        uxrom.cpu_write(0xc001, 0x02);
+        assert_eq!(uxrom.cpu_peek(0x8000), Some(2));
+        uxrom.cpu_write(0xc010, 0x06);
+        assert_eq!(uxrom.cpu_peek(0x8000), Some(6));
+        uxrom.cpu_write(0xc000, 0x08);
+        assert_eq!(uxrom.cpu_peek(0x8000), Some(0));
+    }
+}
@@ -1,12 +1,12 @@
-use crate::rom_file::RomFile;
+use crate::bus::Bus;
+use crate::ppu::Ppu;
+use crate::mapper::Mapper;
+use crate::region::Region;
+use crate::apu::{Apu, APU_STATUS, APU_FRAME_COUNTER};
+use crate::controller::{Controller, CONTROLLER_1_ADDRESS, CONTROLLER_2_ADDRESS};
 
 use log::{debug, info, error, warn};
 
-pub const NES_INTERNAL_RAM: u16 = 0x0000;
-pub const NES_PPU_REGISTERS: u16 = 0x2000;
-pub const NES_APU_IO_REGISTERS: u16 = 0x4000;
-pub const NES_CARTRIDGE_SPACE: u16 = 0x4020;
-
 pub const PPU_CTRL: u16     = 0x2000;
 pub const PPU_MASK: u16     = 0x2001;
 pub const PPU_STATUS: u16   = 0x2002;
@@ -18,22 +18,34 @@ pub const PPU_DATA: u16     = 0x2007;
 pub const OAM_DMA: u16      = 0x4014;
 
 pub const FLAG_NMI_ENABLE: u8               = 0b10000000;
-pub const FLAG_PPU_MASTER_SLAVE: u8         = 0b01000000;
 pub const FLAG_SPRITE_HEIGHT: u8            = 0b00100000;
 pub const FLAG_BACKGROUND_TILE_SELECT: u8   = 0b00010000;
 pub const FLAG_SPRITE_TILE_SELECT: u8       = 0b00001000;
 pub const FLAG_INCREMENT_MODE: u8           = 0b00000100;
 pub const FLAG_NAMETABLE_SELECT: u8         = 0b00000011;
 
+pub const FLAG_EMPHASIZE_BLUE: u8           = 0b10000000;
+pub const FLAG_EMPHASIZE_GREEN: u8          = 0b01000000;
+pub const FLAG_EMPHASIZE_RED: u8            = 0b00100000;
+pub const FLAG_SHOW_SPRITES: u8             = 0b00010000;
+pub const FLAG_SHOW_BACKGROUND: u8          = 0b00001000;
+pub const FLAG_SHOW_SPRITES_LEFT: u8        = 0b00000100;
+pub const FLAG_SHOW_BACKGROUND_LEFT: u8     = 0b00000010;
+pub const FLAG_GREYSCALE: u8                = 0b00000001;
+
+pub const FLAG_VBLANK: u8                   = 0b10000000;
+pub const FLAG_SPRITE_0_HIT: u8             = 0b01000000;
+pub const FLAG_SPRITE_OVERFLOW: u8          = 0b00100000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum AddressingMode {
-    None,
     Immediate,
     Absolute,
     Implied,
     Accumulator,
     AbsoluteX,
     AbsoluteY,
-    ZeroPageIndexed,
+    ZeroPage,
     ZeroPageX,
     ZeroPageY,
     Indirect,
@@ -42,116 +54,357 @@ pub enum AddressingMode {
     Relative,
 }
 
+/// CPU address space of the NES
+/// https://www.nesdev.org/wiki/CPU_memory_map
 pub struct Memory {
-    data: [u8; 0xFFFF + 1],
+    /// 2kB internal RAM, mirrored up to $1FFF
+    ram: [u8; 0x800],
+    /// PPU, its registers are mirrored every 8 bytes up to $3FFF
+    ppu: Ppu,
+    apu: Apu,
+    controllers: [Controller; 2],
+    /// Cartridge ($4020-$FFFF), also wired to the PPU bus
+    cartridge: Box<dyn Mapper>,
+    /// Last value driven on the data bus, returned when reading unmapped addresses
+    open_bus: u8,
+    /// Page requested by the last write to OAMDMA, until the CPU runs the transfer
+    oam_dma_page: Option<u8>,
+    region: Region,
+    /// Fraction of PPU dot left by the last CPU cycles, when the clock ratio is not an integer (PAL)
+    ppu_dots_remainder: u32,
+    /// Level of the NMI output of the PPU at the last sample
+    nmi_line: bool,
+    /// Set on a rising edge of the NMI line, until the CPU takes it
+    nmi_edge: bool,
 }
 
 impl Memory {
-    pub fn new() -> Memory {
+    pub fn new(cartridge: Box<dyn Mapper>) -> Memory {
         let mem = Memory {
-            data: [0; 0xFFFF + 1]
+            ram: [0; 0x800],
+            ppu: Ppu::new(),
+            apu: Apu::new(),
+            controllers: [Controller::new(), Controller::new()],
+            cartridge,
+            open_bus: 0,
+            oam_dma_page: None,
+            region: Region::NTSC,
+            ppu_dots_remainder: 0,
+            nmi_line: false,
+            nmi_edge: false,
         };
 
         return mem;
     }
 
-    /// Load the given ROM into the virtual memory
-    pub fn load(&mut self, rom_file: &RomFile) {
-        if rom_file.get_mapper_type() != 0 {
-            error!("Unsupported mapper");   // For now on, we only support the mapper 0
-        }
+    /// Sets the region of the console, it selects the timings of the PPU and the APU
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
+    }
+
+    pub fn get_ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    /// Gets the cartridge, seen by the PPU through its bus
+    pub fn get_cartridge(&self) -> &dyn Mapper {
+        self.cartridge.as_ref()
+    }
+
+    /// Gets the controller plugged in the given port (0 or 1)
+    pub fn get_controller(&mut self, port: usize) -> &mut Controller {
+        &mut self.controllers[port]
+    }
 
-        self.data[0xc000..0xc000+0x4000].clone_from_slice(&rom_file.data[0x10..0x10+0x4000]);
+    /// Returns whether the PPU has completed a frame since the last call
+    pub fn take_frame_complete(&mut self) -> bool {
+        self.ppu.take_frame_complete()
+    }
 
-        let prg_data = rom_file.prg_data();
-        self.data[0x8000..0x8000+prg_data.len()].clone_from_slice(&prg_data);
+    /// Gets the level of the APU frame counter IRQ output
+    pub fn get_apu_irq(&self) -> bool {
+        self.apu.irq_line()
     }
 
-    /// Read the data at the given address
-    pub fn read(&self, address: u16) -> u8 {
-        self.data[address as usize]
+    /// Gets the level of the cartridge IRQ output
+    pub fn get_mapper_irq(&self) -> bool {
+        self.cartridge.irq_line()
     }
 
-    pub fn write(&mut self, address: u16, val: u8) {
-        self.data[address as usize] = val;
+    /// Runs the rest of the console for one CPU cycle
+    fn run_cycle(&mut self) {
+        let (numerator, denominator) = self.region.get_ppu_dots_per_cpu_cycle();
+        let dots = numerator + self.ppu_dots_remainder;
+        self.ppu_dots_remainder = dots % denominator;
+
+        for _ in 0..dots / denominator {
+            self.ppu.step(&mut self.cartridge);
+            self.sample_nmi_line();
+        }
+        self.cartridge.cpu_tick();
+        self.apu.tick(1);
     }
 
-    // region Specific reading functions
-    pub fn get_nmi_enable(&self) -> bool {
-        self.read(PPU_CTRL) & FLAG_NMI_ENABLE != 0
+    /// Samples the NMI output of the PPU, to detect its rising edges
+    fn sample_nmi_line(&mut self) {
+        let level = self.ppu.nmi_line();
+        self.nmi_edge |= level && !self.nmi_line;
+        self.nmi_line = level;
     }
+}
+
+impl Bus for Memory {
+    /// Read the data at the given address, the access takes one CPU cycle during which the rest of
+    /// the console runs
+    fn read(&mut self, address: u16) -> u8 {
+        self.run_cycle();
 
-    pub fn set_nmi_enable(&mut self, nmi_enable: bool) {
-        let val = self.read(PPU_CTRL);
-        if nmi_enable {
-            self.write(PPU_CTRL, val | FLAG_NMI_ENABLE);
-        } else {
-            self.write(PPU_CTRL, val & !FLAG_NMI_ENABLE);
+        let val = match address {
+            0x0000..=0x1fff => self.ram[(address & 0x07ff) as usize],
+            0x2000..=0x3fff => {
+                let val = self.ppu.read_register(PPU_CTRL | (address & 0x0007), &mut self.cartridge);
+                if self.ppu.take_nmi_cancel() {
+                    self.nmi_edge = false;
+                }
+                val
+            },
+            APU_STATUS => {
+                // Bit 5 is not driven
+                self.apu.read_status() | (self.open_bus & 0b00100000)
+            },
+            CONTROLLER_1_ADDRESS | CONTROLLER_2_ADDRESS => {
+                // Only the low bits are driven by the controller port
+                let port = (address - CONTROLLER_1_ADDRESS) as usize;
+                self.controllers[port].read() | (self.open_bus & 0b11100000)
+            },
+            0x4020..=0xffff => match self.cartridge.cpu_read(address) {
+                Some(val) => val,
+                None => {
+                    debug!("Read from unmapped address 0x{:04x}", address);
+                    self.open_bus
+                }
+            },
+            _ => {
+                debug!("Read from unmapped address 0x{:04x}", address);
+                self.open_bus
+            }
+        };
+
+        self.open_bus = val;
+        self.sample_nmi_line();
+        val
+    }
+
+    /// Write the data at the given address, the access takes one CPU cycle during which the rest of
+    /// the console runs
+    fn write(&mut self, address: u16, val: u8) {
+        self.run_cycle();
+        self.open_bus = val;
+
+        match address {
+            0x0000..=0x1fff => self.ram[(address & 0x07ff) as usize] = val,
+            0x2000..=0x3fff => self.ppu.write_register(PPU_CTRL | (address & 0x0007), val, &mut self.cartridge),
+            OAM_DMA => self.oam_dma_page = Some(val),
+            CONTROLLER_1_ADDRESS => {
+                // The strobe is sent to both controllers
+                self.controllers[0].write(val);
+                self.controllers[1].write(val);
+            },
+            0x4000..=0x4013 | APU_STATUS | APU_FRAME_COUNTER => self.apu.write_register(address, val),
+            0x4020..=0xffff => self.cartridge.cpu_write(address, val),
+            _ => debug!("Write to unmapped address 0x{:04x}", address)
         }
+        self.sample_nmi_line();
     }
 
-    pub fn get_background_tile_select(&self) -> bool {
-        self.read(PPU_CTRL) & FLAG_BACKGROUND_TILE_SELECT != 0
+    fn peek(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1fff => self.ram[(address & 0x07ff) as usize],
+            0x2000..=0x3fff => self.ppu.peek_register(PPU_CTRL | (address & 0x0007)),
+            APU_STATUS => self.apu.peek_status() | (self.open_bus & 0b00100000),
+            CONTROLLER_1_ADDRESS | CONTROLLER_2_ADDRESS => {
+                let port = (address - CONTROLLER_1_ADDRESS) as usize;
+                self.controllers[port].peek() | (self.open_bus & 0b11100000)
+            },
+            0x4020..=0xffff => self.cartridge.cpu_peek(address).unwrap_or(self.open_bus),
+            _ => self.open_bus
+        }
     }
 
-    pub fn set_background_tile_select(&mut self, nmi_enable: bool) {
-        let val = self.read(PPU_CTRL);
-        if nmi_enable {
-            self.write(PPU_CTRL, val | FLAG_BACKGROUND_TILE_SELECT);
-        } else {
-            self.write(PPU_CTRL, val & !FLAG_BACKGROUND_TILE_SELECT);
+    fn tick(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            self.run_cycle();
         }
     }
 
-    pub fn get_background_pattern_table_address_value(&self) -> u16 {
-        // TODO
-        return 0
+    fn take_oam_dma_request(&mut self) -> Option<u8> {
+        self.oam_dma_page.take()
     }
-    // endregion
 
-    // region Memory addressing
-    pub fn get_immediate(&self, address: u16) -> u16 {
-        address + 1
+    fn take_nmi_edge(&mut self) -> bool {
+        std::mem::take(&mut self.nmi_edge)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::mapper::{Cartridge, ProbeMapper};
+    use crate::mapper::nrom::Nrom;
+    use crate::rom_file::Mirroring;
+
+    fn new_memory(prg_rom: Vec<u8>) -> Memory {
+        let cartridge = Cartridge::from_memories(prg_rom, Vec::new(), Mirroring::HORIZONTAL);
+        Memory::new(Box::new(Nrom::new(cartridge, false)))
+    }
+
+    #[test]
+    fn internal_ram_is_mirrored() {
+        let mut memory = new_memory(vec![0; 0x8000]);
+        memory.write(0x0012, 0x34);
+        assert_eq!(memory.read(0x0812), 0x34);
+        assert_eq!(memory.read(0x1012), 0x34);
+        assert_eq!(memory.read(0x1812), 0x34);
+    }
+
+    #[test]
+    fn ppu_runs_three_dots_per_cpu_access() {
+        // LDA #$02, STA $0300,X, INC $00, JMP $8000
+        let mut prg_rom = vec![0; 0x8000];
+        prg_rom[0..11].copy_from_slice(&[0xa9, 0x02, 0x9d, 0x00, 0x03, 0xe6, 0x00, 0x4c, 0x00, 0x80, 0x00]);
+        prg_rom[0x7ffd] = 0x80;
+        let mut memory = new_memory(prg_rom);
+
+        // The reset sequence takes 7 cycles
+        let mut cpu = Cpu::new(&mut memory);
+        assert_eq!((memory.get_ppu().get_scanline(), memory.get_ppu().get_dot()), (0, 21));
+
+        for _ in 0..12 {
+            cpu.step(&mut memory);
+            let ppu = memory.get_ppu();
+            assert_eq!((ppu.get_scanline() * 341 + ppu.get_dot()) as u64, cpu.get_cycles() * 3);
+        }
     }
 
-    pub fn get_zeropage(&self, address: u16) -> u16 {
-        (address + 1) & 0x00ff
+    /// Runs the PPU alone up to the given dot, with the NMI enabled
+    fn run_ppu_to(memory: &mut Memory, scanline: u32, dot: u32) {
+        memory.write(PPU_CTRL, FLAG_NMI_ENABLE);
+        while (memory.ppu.get_scanline(), memory.ppu.get_dot()) != (scanline, dot) {
+            memory.ppu.step(&mut memory.cartridge);
+        }
     }
 
-    pub fn get_zeropage_x(&self, address: u16, x: u8) -> u16 {
-        (address + x as u16) & 0x00ff
+    #[test]
+    fn ppustatus_read_one_dot_before_vblank_suppresses_flag_and_nmi() {
+        let mut memory = new_memory(vec![0; 0x8000]);
+        // The read runs the dots up to the dot 0 of the first vertical blank line
+        run_ppu_to(&mut memory, 240, 338);
+
+        assert_eq!(memory.read(PPU_STATUS) & FLAG_VBLANK, 0);
+        memory.tick(10);
+        assert!(!memory.get_ppu().get_vblank());
+        assert!(!memory.take_nmi_edge());
     }
 
-    pub fn get_zeropage_y(&self, address: u16, y: u8) -> u16 {
-        (address + y as u16) & 0x00ff
+    #[test]
+    fn ppustatus_read_at_vblank_start_suppresses_nmi() {
+        // The read runs the dots up to the dot 1, then the dot 2, of the first vertical blank line
+        for (scanline, dot) in [(240, 339), (240, 340)] {
+            let mut memory = new_memory(vec![0; 0x8000]);
+            run_ppu_to(&mut memory, scanline, dot);
+
+            assert_ne!(memory.read(PPU_STATUS) & FLAG_VBLANK, 0);
+            memory.tick(10);
+            assert!(!memory.take_nmi_edge());
+        }
     }
 
-    pub fn get_absolute(&self, address: u16) -> u16 {
-        self.read(address + 1) as u16 + ((self.read(address + 2) as u16) << 8)
+    #[test]
+    fn ppustatus_read_after_vblank_start_keeps_nmi() {
+        // The read runs the dots up to the dot 3
+        let mut memory = new_memory(vec![0; 0x8000]);
+        run_ppu_to(&mut memory, 241, 0);
+        assert_ne!(memory.read(PPU_STATUS) & FLAG_VBLANK, 0);
+        assert!(memory.take_nmi_edge());
+
+        // Without a read, the NMI is raised on the dot 1
+        let mut memory = new_memory(vec![0; 0x8000]);
+        run_ppu_to(&mut memory, 240, 339);
+        memory.tick(1);
+        assert!(memory.take_nmi_edge());
     }
 
-    pub fn get_absolute_x(&self, address: u16, x: u8) -> u16 {
-        self.read(address + 1 + x as u16) as u16 + ((self.read(address + 2 + x as u16) as u16) << 8)
+    #[test]
+    fn cartridge_sees_the_ppu_accesses_at_their_dots() {
+        let probe = ProbeMapper::new(Cartridge::from_memories(vec![0; 0x8000], Vec::new(), Mirroring::HORIZONTAL));
+        let (ppu_dots, scanlines, addresses) = (probe.ppu_dots.clone(), probe.scanlines.clone(), probe.addresses.clone());
+        let mut memory = Memory::new(Box::new(probe));
+
+        // Background patterns at $0000, sprite patterns at $1000
+        memory.write(PPU_CTRL, FLAG_SPRITE_TILE_SELECT);
+        memory.write(PPU_MASK, FLAG_SHOW_BACKGROUND | FLAG_SHOW_SPRITES);
+        memory.tick(250);
+
+        assert_eq!(ppu_dots.get(), 3 * 252);
+        assert_eq!(*scanlines.borrow(), vec![1, 2]);
+
+        // A12 rises on the pattern fetches of the 8 sprites of each line, from the dot 261
+        let mut a12 = false;
+        let mut rising_edges = Vec::new();
+        for &(dot, address) in addresses.borrow().iter() {
+            if address & 0x1000 != 0 && !a12 {
+                rising_edges.push(dot);
+            }
+            a12 = address & 0x1000 != 0;
+        }
+        let expected: Vec<u64> = (0..2)
+            .flat_map(|scanline| (0..8).map(move |sprite| scanline * 341 + 261 + 8 * sprite))
+            .collect();
+        assert_eq!(rising_edges, expected);
     }
 
-    pub fn get_absolute_y(&self, address: u16, y: u8) -> u16 {
-        self.read(address + 1 + y as u16) as u16 + ((self.read(address + 2 + y as u16) as u16) << 8)
+    #[test]
+    fn oam_dma_copies_page_and_stalls_cpu() {
+        // LDA #$02, STA $4014
+        let mut prg_rom = vec![0; 0x8000];
+        prg_rom[0..5].copy_from_slice(&[0xa9, 0x02, 0x8d, 0x14, 0x40]);
+        prg_rom[0x7ffd] = 0x80;
+        let mut memory = new_memory(prg_rom);
+        for i in 0..0x100 {
+            memory.write(0x0200 + i, i as u8);
+        }
+
+        let mut cpu = Cpu::new(&mut memory);
+        cpu.step(&mut memory);
+        // The DMA starts after an odd number of cycles (7 + 2 + 4) and takes an alignment cycle
+        assert_eq!(cpu.step(&mut memory), 4 + 514);
+        assert_eq!(memory.get_ppu().get_oam()[0x11], 0x11);
+        // The unimplemented bits of the attributes are not stored
+        assert_eq!(memory.get_ppu().get_oam()[0xfe], 0xe2);
     }
 
-    pub fn get_relative(&self, address: u16) -> u16 {
-        let offset = self.read(address + 1) as i8 as i16;
-        let address_with_offset = address as i16 + offset;
-        address_with_offset as u16
+    #[test]
+    fn ppu_registers_are_mirrored() {
+        let mut memory = new_memory(vec![0; 0x8000]);
+        memory.write(0x3ff8, 0x80);
+        assert!(memory.get_ppu().get_nmi_enable());
     }
 
-    pub fn get_indirect_x(&self, address: u16, x: u8) -> u16 {
-        self.read((address + x as u16) % 256) as u16 +
-            (self.read( (address + x as u16 + 1) % 256) as u16 * 256)
+    #[test]
+    fn prg_rom_is_read_only() {
+        let mut memory = new_memory(vec![0; 0x8000]);
+        memory.write(0x8000, 0x12);
+        assert_eq!(memory.read(0x8000), 0x00);
     }
 
-    pub fn get_indirect_y(&self, address: u16, y: u8) -> u16 {
-        self.read(address) as u16 +
-            (self.read((address + 1) % 256) as u16 * 256 + y as u16)
+    #[test]
+    fn unmapped_read_returns_open_bus() {
+        let mut memory = new_memory(vec![0; 0x8000]);
+        memory.write(0x0000, 0x5a);
+        memory.read(0x0000);
+        assert_eq!(memory.read(0x5000), 0x5a);
     }
-    // endregion
 }
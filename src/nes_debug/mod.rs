@@ -1 +1,2 @@
 pub mod sdl_ppu;
+pub mod trace;
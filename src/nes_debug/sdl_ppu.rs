@@ -1,5 +1,6 @@
 use sdl2::render::{Texture, Canvas, RenderTarget, TextureAccess};
-use crate::ppu::Ppu;
+use crate::ppu::{Ppu, SCREEN_WIDTH, SCREEN_HEIGHT};
+use crate::ppu_bus::{PpuBus, get_chr_tile};
 use sdl2::pixels::PixelFormatEnum;
 
 use log::{LevelFilter, Level, log_enabled, debug, error};
@@ -21,14 +22,31 @@ pub fn debug_palette(val: u8) -> [u8; 3] {
     }
 }
 
-pub fn fill_texture_chr_data<P>(texture: &mut Texture, ppu: &Ppu, palette: P) where P: Fn(u8)->[u8;3] {
+pub fn fill_texture_framebuffer<P>(texture: &mut Texture, ppu: &Ppu, palette: P) where P: Fn(u16)->[u8;3] {
+    let framebuffer = ppu.get_framebuffer();
+    let result = texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let color = palette(framebuffer[y * SCREEN_WIDTH + x]);
+                let offset = y * pitch + x * 3;
+                buffer[offset..offset + 3].copy_from_slice(&color);
+            }
+        }
+    });
+
+    if let Err(err) = result {
+        error!("Cannot update the screen texture: {}", err);
+    }
+}
+
+pub fn fill_texture_chr_data<B, P>(texture: &mut Texture, bus: &B, palette: P) where B: PpuBus + ?Sized, P: Fn(u8)->[u8;3] {
     let tile_width = 8 * 3;
     let line_width = 8 * 256 * 3;
     texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
         for x in 0..16 {
             for y in 0..16 {
                 let offset: usize = x as usize * tile_width + y as usize * line_width;
-                let tile = ppu.get_chr_tile(x, y);
+                let tile = get_chr_tile(bus, x * 16 + y * 16 * 16);
                 for i in 0..8 {
                     for j in 0..8 {
                         let color = palette(tile[i][j]);
@@ -0,0 +1,92 @@
+use crate::cpu::{Cpu, Instruction};
+use crate::bus::{Bus, PeekBus};
+use crate::memory::AddressingMode;
+use crate::opcodes::OPCODES;
+
+// https://www.qmtpro.com/~nes/misc/nestest.log
+
+/// Formats the instruction located at PC along with the CPU state, in the nestest log format:
+/// `C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7`
+pub fn trace<B: Bus>(cpu: &Cpu, bus: &B, scanline: u32, dot: u32) -> String {
+    let pc = cpu.get_pc();
+    let op = &OPCODES[bus.peek(pc) as usize];
+
+    let bytes: Vec<String> = (0..op.bytes as u16)
+        .map(|i| format!("{:02X}", bus.peek(pc.wrapping_add(i))))
+        .collect();
+
+    format!(
+        "{:04X}  {:<8} {}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+        pc,
+        bytes.join(" "),
+        if op.official { ' ' } else { '*' },
+        disassemble(cpu, bus),
+        cpu.get_a(), cpu.get_x(), cpu.get_y(), cpu.get_p(), cpu.get_s(),
+        scanline, dot,
+        cpu.get_cycles())
+}
+
+/// Disassembles the instruction located at PC, with the effective address and the value of its
+/// operand computed from the current CPU state
+pub fn disassemble<B: Bus>(cpu: &Cpu, bus: &B) -> String {
+    let pc = cpu.get_pc();
+    let op = &OPCODES[bus.peek(pc) as usize];
+    let mnemonic = get_mnemonic(op.instruction);
+
+    // The effective addresses are computed as the CPU does, without its bus accesses
+    let mut peek_bus = PeekBus(bus);
+    let byte = bus.peek(pc.wrapping_add(1));
+    let word = peek_bus.get_absolute(pc);
+
+    match op.mode {
+        AddressingMode::Implied => mnemonic.to_string(),
+        AddressingMode::Accumulator => format!("{} A", mnemonic),
+        AddressingMode::Immediate => format!("{} #${:02X}", mnemonic, bus.peek(peek_bus.get_immediate(pc))),
+        AddressingMode::ZeroPage => {
+            let address = peek_bus.get_zeropage(pc);
+            format!("{} ${:02X} = {:02X}", mnemonic, address, bus.peek(address))
+        },
+        AddressingMode::ZeroPageX => {
+            let address = peek_bus.get_zeropage_x(pc, cpu.get_x());
+            format!("{} ${:02X},X @ {:02X} = {:02X}", mnemonic, byte, address, bus.peek(address))
+        },
+        AddressingMode::ZeroPageY => {
+            let address = peek_bus.get_zeropage_y(pc, cpu.get_y());
+            format!("{} ${:02X},Y @ {:02X} = {:02X}", mnemonic, byte, address, bus.peek(address))
+        },
+        AddressingMode::Absolute => match op.instruction {
+            Instruction::JMP | Instruction::JSR => format!("{} ${:04X}", mnemonic, word),
+            _ => format!("{} ${:04X} = {:02X}", mnemonic, word, bus.peek(word)),
+        },
+        AddressingMode::AbsoluteX => {
+            let address = peek_bus.get_absolute_x(pc, cpu.get_x(), op.page_penalty).0;
+            format!("{} ${:04X},X @ {:04X} = {:02X}", mnemonic, word, address, bus.peek(address))
+        },
+        AddressingMode::AbsoluteY => {
+            let address = peek_bus.get_absolute_y(pc, cpu.get_y(), op.page_penalty).0;
+            format!("{} ${:04X},Y @ {:04X} = {:02X}", mnemonic, word, address, bus.peek(address))
+        },
+        AddressingMode::Indirect => {
+            format!("{} (${:04X}) = {:04X}", mnemonic, word, peek_bus.get_indirect(pc))
+        },
+        AddressingMode::IndexedIndirect => {
+            let address = peek_bus.get_indirect_x(pc, cpu.get_x());
+            format!("{} (${:02X},X) @ {:02X} = {:04X} = {:02X}", mnemonic, byte,
+                    byte.wrapping_add(cpu.get_x()), address, bus.peek(address))
+        },
+        AddressingMode::IndirectIndexed => {
+            let address = peek_bus.get_indirect_y(pc, cpu.get_y(), op.page_penalty).0;
+            format!("{} (${:02X}),Y = {:04X} @ {:04X} = {:02X}", mnemonic, byte,
+                    address.wrapping_sub(cpu.get_y() as u16), address, bus.peek(address))
+        },
+        AddressingMode::Relative => format!("{} ${:04X}", mnemonic, peek_bus.get_relative(pc)),
+    }
+}
+
+/// Gets the mnemonic of an instruction, using the names found in the nestest log
+fn get_mnemonic(instruction: Instruction) -> String {
+    match instruction {
+        Instruction::ISC => "ISB".to_string(),
+        _ => format!("{:?}", instruction),
+    }
+}
@@ -0,0 +1,287 @@
+use crate::cpu::Instruction;
+use crate::cpu::Instruction::*;
+use crate::memory::AddressingMode;
+use crate::memory::AddressingMode::*;
+
+// https://www.masswerk.at/6502/6502_instruction_set.html
+// https://www.nesdev.org/wiki/CPU_unofficial_opcodes
+
+/// Decoding information of a single opcode
+pub struct Opcode {
+    /// Instruction mnemonic
+    pub instruction: Instruction,
+    /// Addressing mode of the operand
+    pub mode: AddressingMode,
+    /// Length of the instruction in bytes (opcode included)
+    pub bytes: u8,
+    /// Base number of cycles
+    pub cycles: u8,
+    /// True if crossing a page when indexing costs an extra cycle
+    pub page_penalty: bool,
+    /// True for the documented opcodes
+    pub official: bool,
+}
+
+const fn op(instruction: Instruction, mode: AddressingMode, bytes: u8, cycles: u8, page_penalty: bool, official: bool) -> Opcode {
+    Opcode { instruction, mode, bytes, cycles, page_penalty, official }
+}
+
+/// Opcode table indexed by the opcode byte
+pub static OPCODES: [Opcode; 256] = [
+    /* 0x00 */ op(BRK, Implied, 1, 7, false, true),
+    /* 0x01 */ op(ORA, IndexedIndirect, 2, 6, false, true),
+    /* 0x02 */ op(STP, Implied, 1, 2, false, false),
+    /* 0x03 */ op(SLO, IndexedIndirect, 2, 8, false, false),
+    /* 0x04 */ op(NOP, ZeroPage, 2, 3, false, false),
+    /* 0x05 */ op(ORA, ZeroPage, 2, 3, false, true),
+    /* 0x06 */ op(ASL, ZeroPage, 2, 5, false, true),
+    /* 0x07 */ op(SLO, ZeroPage, 2, 5, false, false),
+    /* 0x08 */ op(PHP, Implied, 1, 3, false, true),
+    /* 0x09 */ op(ORA, Immediate, 2, 2, false, true),
+    /* 0x0a */ op(ASL, Accumulator, 1, 2, false, true),
+    /* 0x0b */ op(ANC, Immediate, 2, 2, false, false),
+    /* 0x0c */ op(NOP, Absolute, 3, 4, false, false),
+    /* 0x0d */ op(ORA, Absolute, 3, 4, false, true),
+    /* 0x0e */ op(ASL, Absolute, 3, 6, false, true),
+    /* 0x0f */ op(SLO, Absolute, 3, 6, false, false),
+    /* 0x10 */ op(BPL, Relative, 2, 2, false, true),
+    /* 0x11 */ op(ORA, IndirectIndexed, 2, 5, true, true),
+    /* 0x12 */ op(STP, Implied, 1, 2, false, false),
+    /* 0x13 */ op(SLO, IndirectIndexed, 2, 8, false, false),
+    /* 0x14 */ op(NOP, ZeroPageX, 2, 4, false, false),
+    /* 0x15 */ op(ORA, ZeroPageX, 2, 4, false, true),
+    /* 0x16 */ op(ASL, ZeroPageX, 2, 6, false, true),
+    /* 0x17 */ op(SLO, ZeroPageX, 2, 6, false, false),
+    /* 0x18 */ op(CLC, Implied, 1, 2, false, true),
+    /* 0x19 */ op(ORA, AbsoluteY, 3, 4, true, true),
+    /* 0x1a */ op(NOP, Implied, 1, 2, false, false),
+    /* 0x1b */ op(SLO, AbsoluteY, 3, 7, false, false),
+    /* 0x1c */ op(NOP, AbsoluteX, 3, 4, true, false),
+    /* 0x1d */ op(ORA, AbsoluteX, 3, 4, true, true),
+    /* 0x1e */ op(ASL, AbsoluteX, 3, 7, false, true),
+    /* 0x1f */ op(SLO, AbsoluteX, 3, 7, false, false),
+    /* 0x20 */ op(JSR, Absolute, 3, 6, false, true),
+    /* 0x21 */ op(AND, IndexedIndirect, 2, 6, false, true),
+    /* 0x22 */ op(STP, Implied, 1, 2, false, false),
+    /* 0x23 */ op(RLA, IndexedIndirect, 2, 8, false, false),
+    /* 0x24 */ op(BIT, ZeroPage, 2, 3, false, true),
+    /* 0x25 */ op(AND, ZeroPage, 2, 3, false, true),
+    /* 0x26 */ op(ROL, ZeroPage, 2, 5, false, true),
+    /* 0x27 */ op(RLA, ZeroPage, 2, 5, false, false),
+    /* 0x28 */ op(PLP, Implied, 1, 4, false, true),
+    /* 0x29 */ op(AND, Immediate, 2, 2, false, true),
+    /* 0x2a */ op(ROL, Accumulator, 1, 2, false, true),
+    /* 0x2b */ op(ANC, Immediate, 2, 2, false, false),
+    /* 0x2c */ op(BIT, Absolute, 3, 4, false, true),
+    /* 0x2d */ op(AND, Absolute, 3, 4, false, true),
+    /* 0x2e */ op(ROL, Absolute, 3, 6, false, true),
+    /* 0x2f */ op(RLA, Absolute, 3, 6, false, false),
+    /* 0x30 */ op(BMI, Relative, 2, 2, false, true),
+    /* 0x31 */ op(AND, IndirectIndexed, 2, 5, true, true),
+    /* 0x32 */ op(STP, Implied, 1, 2, false, false),
+    /* 0x33 */ op(RLA, IndirectIndexed, 2, 8, false, false),
+    /* 0x34 */ op(NOP, ZeroPageX, 2, 4, false, false),
+    /* 0x35 */ op(AND, ZeroPageX, 2, 4, false, true),
+    /* 0x36 */ op(ROL, ZeroPageX, 2, 6, false, true),
+    /* 0x37 */ op(RLA, ZeroPageX, 2, 6, false, false),
+    /* 0x38 */ op(SEC, Implied, 1, 2, false, true),
+    /* 0x39 */ op(AND, AbsoluteY, 3, 4, true, true),
+    /* 0x3a */ op(NOP, Implied, 1, 2, false, false),
+    /* 0x3b */ op(RLA, AbsoluteY, 3, 7, false, false),
+    /* 0x3c */ op(NOP, AbsoluteX, 3, 4, true, false),
+    /* 0x3d */ op(AND, AbsoluteX, 3, 4, true, true),
+    /* 0x3e */ op(ROL, AbsoluteX, 3, 7, false, true),
+    /* 0x3f */ op(RLA, AbsoluteX, 3, 7, false, false),
+    /* 0x40 */ op(RTI, Implied, 1, 6, false, true),
+    /* 0x41 */ op(EOR, IndexedIndirect, 2, 6, false, true),
+    /* 0x42 */ op(STP, Implied, 1, 2, false, false),
+    /* 0x43 */ op(SRE, IndexedIndirect, 2, 8, false, false),
+    /* 0x44 */ op(NOP, ZeroPage, 2, 3, false, false),
+    /* 0x45 */ op(EOR, ZeroPage, 2, 3, false, true),
+    /* 0x46 */ op(LSR, ZeroPage, 2, 5, false, true),
+    /* 0x47 */ op(SRE, ZeroPage, 2, 5, false, false),
+    /* 0x48 */ op(PHA, Implied, 1, 3, false, true),
+    /* 0x49 */ op(EOR, Immediate, 2, 2, false, true),
+    /* 0x4a */ op(LSR, Accumulator, 1, 2, false, true),
+    /* 0x4b */ op(ALR, Immediate, 2, 2, false, false),
+    /* 0x4c */ op(JMP, Absolute, 3, 3, false, true),
+    /* 0x4d */ op(EOR, Absolute, 3, 4, false, true),
+    /* 0x4e */ op(LSR, Absolute, 3, 6, false, true),
+    /* 0x4f */ op(SRE, Absolute, 3, 6, false, false),
+    /* 0x50 */ op(BVC, Relative, 2, 2, false, true),
+    /* 0x51 */ op(EOR, IndirectIndexed, 2, 5, true, true),
+    /* 0x52 */ op(STP, Implied, 1, 2, false, false),
+    /* 0x53 */ op(SRE, IndirectIndexed, 2, 8, false, false),
+    /* 0x54 */ op(NOP, ZeroPageX, 2, 4, false, false),
+    /* 0x55 */ op(EOR, ZeroPageX, 2, 4, false, true),
+    /* 0x56 */ op(LSR, ZeroPageX, 2, 6, false, true),
+    /* 0x57 */ op(SRE, ZeroPageX, 2, 6, false, false),
+    /* 0x58 */ op(CLI, Implied, 1, 2, false, true),
+    /* 0x59 */ op(EOR, AbsoluteY, 3, 4, true, true),
+    /* 0x5a */ op(NOP, Implied, 1, 2, false, false),
+    /* 0x5b */ op(SRE, AbsoluteY, 3, 7, false, false),
+    /* 0x5c */ op(NOP, AbsoluteX, 3, 4, true, false),
+    /* 0x5d */ op(EOR, AbsoluteX, 3, 4, true, true),
+    /* 0x5e */ op(LSR, AbsoluteX, 3, 7, false, true),
+    /* 0x5f */ op(SRE, AbsoluteX, 3, 7, false, false),
+    /* 0x60 */ op(RTS, Implied, 1, 6, false, true),
+    /* 0x61 */ op(ADC, IndexedIndirect, 2, 6, false, true),
+    /* 0x62 */ op(STP, Implied, 1, 2, false, false),
+    /* 0x63 */ op(RRA, IndexedIndirect, 2, 8, false, false),
+    /* 0x64 */ op(NOP, ZeroPage, 2, 3, false, false),
+    /* 0x65 */ op(ADC, ZeroPage, 2, 3, false, true),
+    /* 0x66 */ op(ROR, ZeroPage, 2, 5, false, true),
+    /* 0x67 */ op(RRA, ZeroPage, 2, 5, false, false),
+    /* 0x68 */ op(PLA, Implied, 1, 4, false, true),
+    /* 0x69 */ op(ADC, Immediate, 2, 2, false, true),
+    /* 0x6a */ op(ROR, Accumulator, 1, 2, false, true),
+    /* 0x6b */ op(ARR, Immediate, 2, 2, false, false),
+    /* 0x6c */ op(JMP, Indirect, 3, 5, false, true),
+    /* 0x6d */ op(ADC, Absolute, 3, 4, false, true),
+    /* 0x6e */ op(ROR, Absolute, 3, 6, false, true),
+    /* 0x6f */ op(RRA, Absolute, 3, 6, false, false),
+    /* 0x70 */ op(BVS, Relative, 2, 2, false, true),
+    /* 0x71 */ op(ADC, IndirectIndexed, 2, 5, true, true),
+    /* 0x72 */ op(STP, Implied, 1, 2, false, false),
+    /* 0x73 */ op(RRA, IndirectIndexed, 2, 8, false, false),
+    /* 0x74 */ op(NOP, ZeroPageX, 2, 4, false, false),
+    /* 0x75 */ op(ADC, ZeroPageX, 2, 4, false, true),
+    /* 0x76 */ op(ROR, ZeroPageX, 2, 6, false, true),
+    /* 0x77 */ op(RRA, ZeroPageX, 2, 6, false, false),
+    /* 0x78 */ op(SEI, Implied, 1, 2, false, true),
+    /* 0x79 */ op(ADC, AbsoluteY, 3, 4, true, true),
+    /* 0x7a */ op(NOP, Implied, 1, 2, false, false),
+    /* 0x7b */ op(RRA, AbsoluteY, 3, 7, false, false),
+    /* 0x7c */ op(NOP, AbsoluteX, 3, 4, true, false),
+    /* 0x7d */ op(ADC, AbsoluteX, 3, 4, true, true),
+    /* 0x7e */ op(ROR, AbsoluteX, 3, 7, false, true),
+    /* 0x7f */ op(RRA, AbsoluteX, 3, 7, false, false),
+    /* 0x80 */ op(NOP, Immediate, 2, 2, false, false),
+    /* 0x81 */ op(STA, IndexedIndirect, 2, 6, false, true),
+    /* 0x82 */ op(NOP, Immediate, 2, 2, false, false),
+    /* 0x83 */ op(SAX, IndexedIndirect, 2, 6, false, false),
+    /* 0x84 */ op(STY, ZeroPage, 2, 3, false, true),
+    /* 0x85 */ op(STA, ZeroPage, 2, 3, false, true),
+    /* 0x86 */ op(STX, ZeroPage, 2, 3, false, true),
+    /* 0x87 */ op(SAX, ZeroPage, 2, 3, false, false),
+    /* 0x88 */ op(DEY, Implied, 1, 2, false, true),
+    /* 0x89 */ op(NOP, Immediate, 2, 2, false, false),
+    /* 0x8a */ op(TXA, Implied, 1, 2, false, true),
+    /* 0x8b */ op(XAA, Immediate, 2, 2, false, false),
+    /* 0x8c */ op(STY, Absolute, 3, 4, false, true),
+    /* 0x8d */ op(STA, Absolute, 3, 4, false, true),
+    /* 0x8e */ op(STX, Absolute, 3, 4, false, true),
+    /* 0x8f */ op(SAX, Absolute, 3, 4, false, false),
+    /* 0x90 */ op(BCC, Relative, 2, 2, false, true),
+    /* 0x91 */ op(STA, IndirectIndexed, 2, 6, false, true),
+    /* 0x92 */ op(STP, Implied, 1, 2, false, false),
+    /* 0x93 */ op(AHX, IndirectIndexed, 2, 6, false, false),
+    /* 0x94 */ op(STY, ZeroPageX, 2, 4, false, true),
+    /* 0x95 */ op(STA, ZeroPageX, 2, 4, false, true),
+    /* 0x96 */ op(STX, ZeroPageY, 2, 4, false, true),
+    /* 0x97 */ op(SAX, ZeroPageY, 2, 4, false, false),
+    /* 0x98 */ op(TYA, Implied, 1, 2, false, true),
+    /* 0x99 */ op(STA, AbsoluteY, 3, 5, false, true),
+    /* 0x9a */ op(TXS, Implied, 1, 2, false, true),
+    /* 0x9b */ op(TAS, AbsoluteY, 3, 5, false, false),
+    /* 0x9c */ op(SHY, AbsoluteX, 3, 5, false, false),
+    /* 0x9d */ op(STA, AbsoluteX, 3, 5, false, true),
+    /* 0x9e */ op(SHX, AbsoluteY, 3, 5, false, false),
+    /* 0x9f */ op(AHX, AbsoluteY, 3, 5, false, false),
+    /* 0xa0 */ op(LDY, Immediate, 2, 2, false, true),
+    /* 0xa1 */ op(LDA, IndexedIndirect, 2, 6, false, true),
+    /* 0xa2 */ op(LDX, Immediate, 2, 2, false, true),
+    /* 0xa3 */ op(LAX, IndexedIndirect, 2, 6, false, false),
+    /* 0xa4 */ op(LDY, ZeroPage, 2, 3, false, true),
+    /* 0xa5 */ op(LDA, ZeroPage, 2, 3, false, true),
+    /* 0xa6 */ op(LDX, ZeroPage, 2, 3, false, true),
+    /* 0xa7 */ op(LAX, ZeroPage, 2, 3, false, false),
+    /* 0xa8 */ op(TAY, Implied, 1, 2, false, true),
+    /* 0xa9 */ op(LDA, Immediate, 2, 2, false, true),
+    /* 0xaa */ op(TAX, Implied, 1, 2, false, true),
+    /* 0xab */ op(LAX, Immediate, 2, 2, false, false),
+    /* 0xac */ op(LDY, Absolute, 3, 4, false, true),
+    /* 0xad */ op(LDA, Absolute, 3, 4, false, true),
+    /* 0xae */ op(LDX, Absolute, 3, 4, false, true),
+    /* 0xaf */ op(LAX, Absolute, 3, 4, false, false),
+    /* 0xb0 */ op(BCS, Relative, 2, 2, false, true),
+    /* 0xb1 */ op(LDA, IndirectIndexed, 2, 5, true, true),
+    /* 0xb2 */ op(STP, Implied, 1, 2, false, false),
+    /* 0xb3 */ op(LAX, IndirectIndexed, 2, 5, true, false),
+    /* 0xb4 */ op(LDY, ZeroPageX, 2, 4, false, true),
+    /* 0xb5 */ op(LDA, ZeroPageX, 2, 4, false, true),
+    /* 0xb6 */ op(LDX, ZeroPageY, 2, 4, false, true),
+    /* 0xb7 */ op(LAX, ZeroPageY, 2, 4, false, false),
+    /* 0xb8 */ op(CLV, Implied, 1, 2, false, true),
+    /* 0xb9 */ op(LDA, AbsoluteY, 3, 4, true, true),
+    /* 0xba */ op(TSX, Implied, 1, 2, false, true),
+    /* 0xbb */ op(LAS, AbsoluteY, 3, 4, true, false),
+    /* 0xbc */ op(LDY, AbsoluteX, 3, 4, true, true),
+    /* 0xbd */ op(LDA, AbsoluteX, 3, 4, true, true),
+    /* 0xbe */ op(LDX, AbsoluteY, 3, 4, true, true),
+    /* 0xbf */ op(LAX, AbsoluteY, 3, 4, true, false),
+    /* 0xc0 */ op(CPY, Immediate, 2, 2, false, true),
+    /* 0xc1 */ op(CMP, IndexedIndirect, 2, 6, false, true),
+    /* 0xc2 */ op(NOP, Immediate, 2, 2, false, false),
+    /* 0xc3 */ op(DCP, IndexedIndirect, 2, 8, false, false),
+    /* 0xc4 */ op(CPY, ZeroPage, 2, 3, false, true),
+    /* 0xc5 */ op(CMP, ZeroPage, 2, 3, false, true),
+    /* 0xc6 */ op(DEC, ZeroPage, 2, 5, false, true),
+    /* 0xc7 */ op(DCP, ZeroPage, 2, 5, false, false),
+    /* 0xc8 */ op(INY, Implied, 1, 2, false, true),
+    /* 0xc9 */ op(CMP, Immediate, 2, 2, false, true),
+    /* 0xca */ op(DEX, Implied, 1, 2, false, true),
+    /* 0xcb */ op(AXS, Immediate, 2, 2, false, false),
+    /* 0xcc */ op(CPY, Absolute, 3, 4, false, true),
+    /* 0xcd */ op(CMP, Absolute, 3, 4, false, true),
+    /* 0xce */ op(DEC, Absolute, 3, 6, false, true),
+    /* 0xcf */ op(DCP, Absolute, 3, 6, false, false),
+    /* 0xd0 */ op(BNE, Relative, 2, 2, false, true),
+    /* 0xd1 */ op(CMP, IndirectIndexed, 2, 5, true, true),
+    /* 0xd2 */ op(STP, Implied, 1, 2, false, false),
+    /* 0xd3 */ op(DCP, IndirectIndexed, 2, 8, false, false),
+    /* 0xd4 */ op(NOP, ZeroPageX, 2, 4, false, false),
+    /* 0xd5 */ op(CMP, ZeroPageX, 2, 4, false, true),
+    /* 0xd6 */ op(DEC, ZeroPageX, 2, 6, false, true),
+    /* 0xd7 */ op(DCP, ZeroPageX, 2, 6, false, false),
+    /* 0xd8 */ op(CLD, Implied, 1, 2, false, true),
+    /* 0xd9 */ op(CMP, AbsoluteY, 3, 4, true, true),
+    /* 0xda */ op(NOP, Implied, 1, 2, false, false),
+    /* 0xdb */ op(DCP, AbsoluteY, 3, 7, false, false),
+    /* 0xdc */ op(NOP, AbsoluteX, 3, 4, true, false),
+    /* 0xdd */ op(CMP, AbsoluteX, 3, 4, true, true),
+    /* 0xde */ op(DEC, AbsoluteX, 3, 7, false, true),
+    /* 0xdf */ op(DCP, AbsoluteX, 3, 7, false, false),
+    /* 0xe0 */ op(CPX, Immediate, 2, 2, false, true),
+    /* 0xe1 */ op(SBC, IndexedIndirect, 2, 6, false, true),
+    /* 0xe2 */ op(NOP, Immediate, 2, 2, false, false),
+    /* 0xe3 */ op(ISC, IndexedIndirect, 2, 8, false, false),
+    /* 0xe4 */ op(CPX, ZeroPage, 2, 3, false, true),
+    /* 0xe5 */ op(SBC, ZeroPage, 2, 3, false, true),
+    /* 0xe6 */ op(INC, ZeroPage, 2, 5, false, true),
+    /* 0xe7 */ op(ISC, ZeroPage, 2, 5, false, false),
+    /* 0xe8 */ op(INX, Implied, 1, 2, false, true),
+    /* 0xe9 */ op(SBC, Immediate, 2, 2, false, true),
+    /* 0xea */ op(NOP, Implied, 1, 2, false, true),
+    /* 0xeb */ op(SBC, Immediate, 2, 2, false, false),
+    /* 0xec */ op(CPX, Absolute, 3, 4, false, true),
+    /* 0xed */ op(SBC, Absolute, 3, 4, false, true),
+    /* 0xee */ op(INC, Absolute, 3, 6, false, true),
+    /* 0xef */ op(ISC, Absolute, 3, 6, false, false),
+    /* 0xf0 */ op(BEQ, Relative, 2, 2, false, true),
+    /* 0xf1 */ op(SBC, IndirectIndexed, 2, 5, true, true),
+    /* 0xf2 */ op(STP, Implied, 1, 2, false, false),
+    /* 0xf3 */ op(ISC, IndirectIndexed, 2, 8, false, false),
+    /* 0xf4 */ op(NOP, ZeroPageX, 2, 4, false, false),
+    /* 0xf5 */ op(SBC, ZeroPageX, 2, 4, false, true),
+    /* 0xf6 */ op(INC, ZeroPageX, 2, 6, false, true),
+    /* 0xf7 */ op(ISC, ZeroPageX, 2, 6, false, false),
+    /* 0xf8 */ op(SED, Implied, 1, 2, false, true),
+    /* 0xf9 */ op(SBC, AbsoluteY, 3, 4, true, true),
+    /* 0xfa */ op(NOP, Implied, 1, 2, false, false),
+    /* 0xfb */ op(ISC, AbsoluteY, 3, 7, false, false),
+    /* 0xfc */ op(NOP, AbsoluteX, 3, 4, true, false),
+    /* 0xfd */ op(SBC, AbsoluteX, 3, 4, true, true),
+    /* 0xfe */ op(INC, AbsoluteX, 3, 7, false, true),
+    /* 0xff */ op(ISC, AbsoluteX, 3, 7, false, false),
+];
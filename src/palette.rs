@@ -0,0 +1,240 @@
+// https://www.nesdev.org/wiki/PPU_palettes
+// https://www.nesdev.org/wiki/NTSC_video
+
+use std::f32::consts::PI;
+use std::fs;
+use std::io;
+
+/// Number of colors of the NES master palette
+pub const PALETTE_SIZE: usize = 64;
+
+/// Size of a .pal file with only the 64 colors
+const PAL_FILE_SIZE: usize = PALETTE_SIZE * 3;
+/// Size of a .pal file with the 64 colors for each of the 8 combinations of emphasis bits
+const PAL_FILE_WITH_EMPHASIS_SIZE: usize = 8 * PAL_FILE_SIZE;
+
+// Voltage levels of the composite signal, relative to the sync level, for the 4 luminance levels
+const SIGNAL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const SIGNAL_BLACK: f32 = 0.518;
+const SIGNAL_WHITE: f32 = 1.962;
+/// Attenuation of the signal during the phases of an emphasized color
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+/// Attenuation of the RGB channels of the colors not emphasized, for the palettes without the
+/// emphasis variants
+const RGB_EMPHASIS_ATTENUATION: f32 = 0.816;
+
+/// Parameters of the NTSC decoder used to generate the palette, like the knobs of a TV set
+#[derive(Debug, Clone, Copy)]
+pub struct NtscParameters {
+    /// Hue rotation in degrees
+    pub hue: f32,
+    /// Saturation multiplier
+    pub saturation: f32,
+    /// Contrast multiplier
+    pub contrast: f32,
+    /// Brightness offset, added to the luminance
+    pub brightness: f32,
+}
+
+impl Default for NtscParameters {
+    fn default() -> NtscParameters {
+        NtscParameters {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+        }
+    }
+}
+
+/// Master palette converting the 6-bit colors output by the PPU to RGB
+pub struct Palette {
+    /// RGB colors, 64 entries or 512 entries when the emphasis variants are known
+    colors: Vec<[u8; 3]>,
+}
+
+impl Palette {
+    /// Reads a palette file, either 64 RGB colors (192 bytes) or 64 RGB colors for each of the 8
+    /// combinations of emphasis bits (1536 bytes)
+    pub fn from_file(path: &str) -> io::Result<Palette> {
+        let data = fs::read(path)?;
+        Palette::from_bytes(&data)
+    }
+
+    /// Reads a palette from the content of a .pal file
+    pub fn from_bytes(data: &[u8]) -> io::Result<Palette> {
+        if data.len() != PAL_FILE_SIZE && data.len() != PAL_FILE_WITH_EMPHASIS_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid palette size: {} bytes", data.len())));
+        }
+
+        let colors = data.chunks(3)
+            .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+            .collect();
+
+        Ok(Palette { colors })
+    }
+
+    /// Generates the palette, with the emphasis variants, by decoding the composite signal of each
+    /// color with the given NTSC parameters
+    pub fn generate(parameters: &NtscParameters) -> Palette {
+        let colors = (0..PALETTE_SIZE * 8)
+            .map(|pixel| decode_ntsc_color(pixel as u16, parameters))
+            .collect();
+
+        Palette { colors }
+    }
+
+    /// Gets the RGB value of a pixel output by the PPU: the 6-bit color in the bits 0-5 and the
+    /// emphasis bits (red, green, blue) in the bits 6-8
+    pub fn get_color(&self, pixel: u16) -> [u8; 3] {
+        let pixel = (pixel as usize) % (PALETTE_SIZE * 8);
+        if pixel < self.colors.len() {
+            return self.colors[pixel];
+        }
+
+        // Without the emphasis variants, the channels of the colors not emphasized are attenuated,
+        // except for the columns $E and $F
+        let mut rgb = self.colors[pixel % PALETTE_SIZE];
+        if (pixel & 0x0f) >= 0x0e {
+            return rgb;
+        }
+        let emphasis = pixel / PALETTE_SIZE;
+        for (channel, val) in rgb.iter_mut().enumerate() {
+            let others = emphasis & !(1 << channel);
+            if others != 0 {
+                *val = (*val as f32 * RGB_EMPHASIS_ATTENUATION).round() as u8;
+            }
+        }
+        rgb
+    }
+}
+
+// region NTSC decoding
+/// Gets the level of the composite signal of a color (6-bit color and 3 emphasis bits) during one of
+/// the 12 phases of the color subcarrier
+fn get_signal_level(pixel: u16, phase: u16) -> f32 {
+    let color = pixel & 0x0f;
+    // Columns $E and $F are black
+    let level = if color < 0x0e { ((pixel >> 4) & 0x03) as usize } else { 1 };
+
+    // The hue selects in which 6 of the 12 phases the signal is high, column 0 is always high and
+    // columns $D to $F are always low
+    let in_color_phase = |color: u16| (color + phase) % 12 < 6;
+    let high = match color {
+        0x00 => true,
+        0x0d..=0x0f => false,
+        _ => in_color_phase(color),
+    };
+    let mut signal = if high { SIGNAL_HIGH[level] } else { SIGNAL_LOW[level] };
+
+    // Each emphasis bit attenuates the signal during the phases of its color (red, green and blue),
+    // the columns $E and $F are not affected
+    let emphasis = pixel >> 6;
+    if color >= 0x0e {
+        return signal;
+    }
+    if (emphasis & 0b001 != 0 && in_color_phase(0))
+        || (emphasis & 0b010 != 0 && in_color_phase(4))
+        || (emphasis & 0b100 != 0 && in_color_phase(8)) {
+        signal *= EMPHASIS_ATTENUATION;
+    }
+
+    signal
+}
+
+/// Decodes a color (6-bit color and 3 emphasis bits) to RGB: the signal is sampled over a period of
+/// the color subcarrier and demodulated to YIQ
+fn decode_ntsc_color(pixel: u16, parameters: &NtscParameters) -> [u8; 3] {
+    let mut y = 0.0;
+    let mut i = 0.0;
+    let mut q = 0.0;
+
+    for phase in 0..12 {
+        let signal = (get_signal_level(pixel, phase) - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK);
+        let angle = PI * (phase as f32 + 3.0) / 6.0 + parameters.hue.to_radians();
+
+        y += signal;
+        i += signal * angle.cos();
+        q += signal * angle.sin();
+    }
+
+    let y = (y / 12.0) * parameters.contrast + parameters.brightness;
+    let i = (i / 12.0) * parameters.saturation * parameters.contrast;
+    let q = (q / 12.0) * parameters.saturation * parameters.contrast;
+
+    let to_byte = |val: f32| (val.clamp(0.0, 1.0) * 255.0).round() as u8;
+    [
+        to_byte(y + 0.946882 * i + 0.623557 * q),
+        to_byte(y - 0.274788 * i - 0.635691 * q),
+        to_byte(y - 1.108545 * i + 1.709007 * q),
+    ]
+}
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_file_sizes() {
+        assert_eq!(Palette::from_bytes(&[0x10; PAL_FILE_SIZE]).unwrap().colors.len(), 64);
+        assert_eq!(Palette::from_bytes(&[0x10; PAL_FILE_WITH_EMPHASIS_SIZE]).unwrap().colors.len(), 512);
+        assert!(Palette::from_bytes(&[0x10; 100]).is_err());
+    }
+
+    #[test]
+    fn generated_palette_greys() {
+        let palette = Palette::generate(&NtscParameters::default());
+
+        // $0F is black and $30 is white
+        assert_eq!(palette.get_color(0x0f), [0, 0, 0]);
+        assert_eq!(palette.get_color(0x30), [255, 255, 255]);
+
+        // Column 0 has no saturation
+        let [r, g, b] = palette.get_color(0x10);
+        assert!(r == g && g == b);
+    }
+
+    #[test]
+    fn generated_palette_hues() {
+        let palette = Palette::generate(&NtscParameters::default());
+
+        let [r, g, b] = palette.get_color(0x16);
+        assert!(r > g && r > b, "$16 should be red: {:?}", [r, g, b]);
+        let [r, g, b] = palette.get_color(0x1a);
+        assert!(g > r && g > b, "$1A should be green: {:?}", [r, g, b]);
+        let [r, g, b] = palette.get_color(0x12);
+        assert!(b > r && b > g, "$12 should be blue: {:?}", [r, g, b]);
+    }
+
+    #[test]
+    fn emphasis_without_variants_attenuates_other_channels() {
+        let palette = Palette::from_bytes(&[200; PAL_FILE_SIZE]).unwrap();
+
+        // Red emphasis
+        assert_eq!(palette.get_color(0b001_110000), [200, 163, 163]);
+        // Green and blue emphasis
+        assert_eq!(palette.get_color(0b110_110000), [163, 163, 163]);
+        assert_eq!(palette.get_color(0b000_110000), [200, 200, 200]);
+        // The columns $E and $F are not affected
+        assert_eq!(palette.get_color(0b001_111110), [200, 200, 200]);
+    }
+
+    #[test]
+    fn generated_emphasis_darkens_colors() {
+        let palette = Palette::generate(&NtscParameters::default());
+
+        let [r, g, b] = palette.get_color(0x30);
+        let [er, eg, eb] = palette.get_color(0b001_110000);
+        assert!(er <= r && eg < g && eb < b);
+
+        // The signal of the columns $E and $F is not affected
+        for phase in 0..12 {
+            assert_eq!(get_signal_level(0b111_001110, phase), get_signal_level(0x0e, phase));
+            assert_eq!(get_signal_level(0b011_111111, phase), get_signal_level(0x3f, phase));
+        }
+    }
+}
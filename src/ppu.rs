@@ -1,107 +1,1106 @@
-use crate::memory::{Memory, PPU_STATUS, PPU_CTRL};
-use crate::rom_file::RomFile;
-use crate::cpu::{FLAG_VBLANK, FLAG_NMI_OCCURRED};
+use crate::memory::*;
+use crate::ppu_bus::PpuBus;
+use crate::region::Region;
 
 // https://wiki.nesdev.com/w/index.php/PPU_registers#Status_.28.242002.29_.3C_read
+// https://www.nesdev.org/wiki/PPU_scrolling
 // https://emudev.de/nes-emulator/cartridge-loading-pattern-tables-and-ppu-registers/
 // https://github.com/FartingDeveloper/NES-emulator/blob/master/PPU.cpp
 
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+const PALETTE_ADDRESS: u16 = 0x3f00;
+const NAMETABLE_ADDRESS: u16 = 0x2000;
+const ATTRIBUTE_TABLE_OFFSET: u16 = 0x03c0;
+const SPRITE_PALETTE_ADDRESS: u16 = 0x3f10;
+
+/// Number of sprites drawn on a scanline
+const SPRITES_PER_LINE: usize = 8;
+
+// Sprite attributes (byte 2 of an OAM entry)
+const SPRITE_FLIP_VERTICAL: u8      = 0b10000000;
+const SPRITE_FLIP_HORIZONTAL: u8    = 0b01000000;
+const SPRITE_BEHIND_BACKGROUND: u8  = 0b00100000;
+const SPRITE_PALETTE: u8            = 0b00000011;
+/// Bits of the attributes implemented in the OAM memory, the others are always read as 0
+const SPRITE_ATTRIBUTES_MASK: u8    = 0b11100011;
+
 pub struct Ppu {
+    region: Region,
     cycles: u32,
     scanline: u32,
-    vram: [u8; 0x4000],
-    nmi_occurred: bool,
+    /// Number of frames since power-up, the pre-render line of the odd frames is one dot shorter
+    frame: u64,
+    /// Set when a frame has been fully rendered (start of the vertical blank)
+    frame_complete: bool,
+
+    /// Palette RAM ($3F00-$3F1F), the rest of the memory is accessed through the PPU bus
+    palette: [u8; 0x20],
+
+    // region Registers
+    /// PPUCTRL ($2000)
+    ctrl: u8,
+    /// PPUMASK ($2001)
+    mask: u8,
+    /// PPUSTATUS ($2002)
+    status: u8,
+    /// OAMADDR ($2003)
+    oam_addr: u8,
+    /// Last value written to or read from a register, returned by the write-only registers and the
+    /// unused bits of PPUSTATUS
+    io_latch: u8,
+    /// Buffer of the delayed PPUDATA reads
+    read_buffer: u8,
+    // endregion
+
+    // region Internal scrolling registers
+    /// Current VRAM address (15 bits)
+    v: u16,
+    /// Temporary VRAM address (15 bits), the address of the top-left onscreen tile
+    t: u16,
+    /// Fine X scroll (3 bits)
+    x: u8,
+    /// First or second write toggle of PPUSCROLL and PPUADDR
+    w: bool,
+    // endregion
+
+    // region Background rendering
+    /// Latches filled by the memory fetches of the next tile
+    next_tile_id: u8,
+    next_tile_palette: u8,
+    next_tile_pattern_lo: u8,
+    next_tile_pattern_hi: u8,
+    /// Shift registers holding the pattern bits of the current and next tiles
+    bg_pattern_shifter_lo: u16,
+    bg_pattern_shifter_hi: u16,
+    /// Shift registers holding the palette bits, expanded to one bit per pixel
+    bg_palette_shifter_lo: u16,
+    bg_palette_shifter_hi: u16,
+    // endregion
+
+    // region Sprite rendering
+    /// Primary OAM, 64 sprites of 4 bytes: Y, tile index, attributes, X
+    oam: [u8; 0x100],
+    /// Secondary OAM, the sprites found by the evaluation for the next scanline
+    secondary_oam: [u8; 4 * SPRITES_PER_LINE],
+    /// Number of sprites found by the evaluation for the next scanline
+    next_sprite_count: usize,
+    /// Whether the sprite 0 was found by the evaluation for the next scanline
+    next_sprite_zero: bool,
+    /// Number of sprites of the current scanline
+    sprite_count: usize,
+    /// Whether the first sprite of the current scanline is the sprite 0
+    sprite_zero: bool,
+    /// Pattern bits, attributes and X position of the sprites of the current scanline
+    sprite_pattern_lo: [u8; SPRITES_PER_LINE],
+    sprite_pattern_hi: [u8; SPRITES_PER_LINE],
+    sprite_attributes: [u8; SPRITES_PER_LINE],
+    sprite_x: [u8; SPRITES_PER_LINE],
+    // endregion
+
+    /// Color of each pixel of the frame: the 6-bit color in the bits 0-5 and the emphasis bits of
+    /// PPUMASK (red, green, blue) in the bits 6-8
+    framebuffer: Vec<u16>,
+
+    /// Set when PPUSTATUS is read just before the vertical blank starts, the VBLANK flag is then not
+    /// set for this frame
+    suppress_vblank: bool,
+    /// Set when PPUSTATUS is read 1 or 2 dots after the VBLANK flag is set, the flag is read set but
+    /// the NMI is cancelled before the CPU sees it
+    cancel_nmi: bool,
 }
 
 impl Ppu {
     pub fn new() -> Ppu {
         return Ppu {
+            region: Region::NTSC,
             cycles: 0,
             scanline: 0,
-            vram: [0; 0x4000],
-            nmi_occurred: false,
+            frame: 0,
+            frame_complete: false,
+            palette: [0; 0x20],
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_addr: 0,
+            io_latch: 0,
+            read_buffer: 0,
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            next_tile_id: 0,
+            next_tile_palette: 0,
+            next_tile_pattern_lo: 0,
+            next_tile_pattern_hi: 0,
+            bg_pattern_shifter_lo: 0,
+            bg_pattern_shifter_hi: 0,
+            bg_palette_shifter_lo: 0,
+            bg_palette_shifter_hi: 0,
+            oam: [0; 0x100],
+            secondary_oam: [0xff; 4 * SPRITES_PER_LINE],
+            next_sprite_count: 0,
+            next_sprite_zero: false,
+            sprite_count: 0,
+            sprite_zero: false,
+            sprite_pattern_lo: [0; SPRITES_PER_LINE],
+            sprite_pattern_hi: [0; SPRITES_PER_LINE],
+            sprite_attributes: [0; SPRITES_PER_LINE],
+            sprite_x: [0; SPRITES_PER_LINE],
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            suppress_vblank: false,
+            cancel_nmi: false,
         };
     }
 
-    pub fn load(&mut self, rom_file: &RomFile) {
-        let chr_data = rom_file.chr_data();
-        self.vram[0x0000..(0x0000 + rom_file.chr_size() as usize)].clone_from_slice(&chr_data);
+    /// Sets the region, it selects the number of scanlines and the vertical blank timing
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
     }
 
-    pub fn vram_data(&self) -> &[u8;0x4000] {
-        return &self.vram;
+    /// Gets the last scanline of the frame, the pre-render line
+    fn get_pre_render_scanline(&self) -> u32 {
+        self.region.get_scanlines() - 1
     }
 
-    pub fn read_vram(&self, address: u16) -> u8 {
-        return self.vram[address as usize];
+    /// Writes the VRAM, the palette RAM is internal and the rest goes through the PPU bus
+    fn write_vram<B: PpuBus>(&mut self, address: u16, val: u8, bus: &mut B) {
+        let address = address & 0x3fff;
+        if address >= PALETTE_ADDRESS {
+            self.palette[get_palette_index(address)] = val;
+        } else {
+            bus.write(address, val);
+        }
     }
 
-    pub fn write_vram(&mut self, address: u16, val: u8) {
-        self.vram[address as usize] = val;
+    /// Reads a 6-bit color of the palette RAM, the greyscale mode of PPUMASK keeps only the
+    /// luminance of the color (column 0)
+    fn read_palette(&self, address: u16) -> u8 {
+        let color = self.palette[get_palette_index(address)] & 0b00111111;
+        if self.mask & FLAG_GREYSCALE != 0 { color & 0b00110000 } else { color }
     }
 
-    pub fn get_chr_tile(&self, x: u8, y:u8) -> [[u8;8]; 8] {
-        let mut result = [[0;8]; 8];
-        let offset: usize = (x as usize * 8 * 2) + (y as usize * 16 * 8 * 2);
+    /// Advances the PPU by one dot, the memory accesses of the dot are issued on the given bus
+    pub fn step<B: PpuBus>(&mut self, bus: &mut B) {
+        self.cycles += 1;
 
-        for i in 0..8 {
-            for j in 0..8 {
-                let lb = ((self.vram[i + offset] & (0b10000000 >> j)) >> (7-j));
-                let hb = ((self.vram[i + 8 + offset] & (0b10000000 >> j)) >> (7-j)) << 1;
+        let pre_render_scanline = self.get_pre_render_scanline();
 
-                result[i][j] = hb | lb;
-            }
+        // On odd frames with rendering enabled, the last dot of the pre-render line is skipped (NTSC
+        // only)
+        if self.scanline == pre_render_scanline && self.cycles == 340 && self.frame % 2 == 1
+            && self.is_rendering_enabled() && self.region.has_odd_frame_skip() {
+            self.cycles += 1;
         }
 
-        return result;
-    }
-
-    pub fn step(&mut self, memory: &mut Memory) {
-        self.cycles += 1;
-
         if self.cycles > 340 {
             self.cycles -= 341;
-            self.scanline += 1
+            self.scanline += 1;
+
+            if self.scanline > pre_render_scanline {
+                self.scanline = 0;
+                self.frame += 1;
+            }
+            bus.notify_scanline(self.scanline);
         }
 
-        if 0 <= self.scanline && self.scanline <= 239 {
-            // Drawing
-        } else if self.scanline == 241 && self.cycles == 1 {
+        if self.scanline <= 239 || self.scanline == pre_render_scanline {
+            // Drawing, the pre-render line does the same memory fetches as the visible lines
+            if self.is_rendering_enabled() {
+                self.render_background_dot(bus);
+                self.render_sprites_dot(bus);
+            }
+
+            if self.scanline <= 239 && 1 <= self.cycles && self.cycles <= 256 {
+                self.output_pixel();
+            }
+        }
+
+        if self.scanline == self.region.get_vblank_scanline() && self.cycles == 1 {
             // VBlank
-            self.set_vblank(memory, true);
-            self.nmi_occurred = true;
-        } else if self.scanline == 261 && self.cycles == 1 {
+            if !self.suppress_vblank {
+                self.set_vblank(true);
+            }
+            self.suppress_vblank = false;
+            self.frame_complete = true;
+        } else if self.scanline == pre_render_scanline && self.cycles == 1 {
             // VBlank off / pre-render line
-            self.set_vblank(memory, false);
-            self.nmi_occurred = false;
+            self.set_vblank(false);
+            self.status &= !(FLAG_SPRITE_0_HIT | FLAG_SPRITE_OVERFLOW);
         }
+
+        bus.tick();
     }
 
-    /// Gets the VBLANK status
-    pub fn get_vblank(&self, memory: &Memory) -> bool {
-        memory.read(PPU_STATUS) & 0b10000000 != 0
+    /// Gets the current scanline, the pre-render line is the last one
+    pub fn get_scanline(&self) -> u32 {
+        self.scanline
     }
 
-    /// Sets the VBLANK status
-    pub fn set_vblank(&self, memory: &mut Memory, status: bool) {
-        let current_status = memory.read(PPU_STATUS);
-        let updated_status =
-            if status {
-                current_status | FLAG_VBLANK
+    /// Gets the current dot (0-340) of the scanline
+    pub fn get_dot(&self) -> u32 {
+        self.cycles
+    }
+
+    /// Gets the number of frames since power-up
+    pub fn get_frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Returns whether a frame has been completed since the last call, the framebuffer then holds
+    /// the whole picture until the pre-render line
+    pub fn take_frame_complete(&mut self) -> bool {
+        let frame_complete = self.frame_complete;
+        self.frame_complete = false;
+        frame_complete
+    }
+
+    /// Gets the content of the primary OAM
+    pub fn get_oam(&self) -> &[u8; 0x100] {
+        &self.oam
+    }
+
+    /// Gets the palette index of each pixel of the last frame, line by line
+    pub fn get_framebuffer(&self) -> &[u16] {
+        &self.framebuffer
+    }
+
+    // region Rendering
+    // https://www.nesdev.org/wiki/PPU_rendering
+
+    fn is_rendering_enabled(&self) -> bool {
+        self.mask & (FLAG_SHOW_BACKGROUND | FLAG_SHOW_SPRITES) != 0
+    }
+
+    /// Runs the background fetches, shifts and scrolling updates of the current dot
+    fn render_background_dot<B: PpuBus>(&mut self, bus: &mut B) {
+        let dot = self.cycles;
+
+        if (2..=257).contains(&dot) || (322..=337).contains(&dot) {
+            self.shift_background();
+
+            // The fetched tile is loaded at the start of the fetches of the following one
+            if dot % 8 == 1 {
+                self.load_background_shifters();
+            }
+        }
+
+        if (1..=256).contains(&dot) || (321..=336).contains(&dot) {
+            // Each tile takes 8 dots: nametable, attribute, pattern low and pattern high bytes are
+            // fetched in 2 dots each
+            match (dot - 1) % 8 {
+                0 => self.next_tile_id = bus.read(NAMETABLE_ADDRESS | (self.v & 0x0fff)),
+                2 => {
+                    // One attribute byte covers 4x4 tiles, split in four 2x2 tiles areas
+                    let address = NAMETABLE_ADDRESS | ATTRIBUTE_TABLE_OFFSET
+                        | (self.v & 0x0c00)
+                        | ((self.v >> 4) & 0x38)
+                        | ((self.v >> 2) & 0x07);
+                    let mut attribute = bus.read(address);
+                    if self.get_coarse_y() & 0x02 != 0 {
+                        attribute >>= 4;
+                    }
+                    if self.get_coarse_x() & 0x02 != 0 {
+                        attribute >>= 2;
+                    }
+                    self.next_tile_palette = attribute & 0x03;
+                },
+                4 => self.next_tile_pattern_lo = bus.read(self.get_background_pattern_address()),
+                6 => self.next_tile_pattern_hi = bus.read(self.get_background_pattern_address() + 8),
+                7 => self.increment_coarse_x(),
+                _ => {}
+            }
+        } else if dot == 337 || dot == 339 {
+            // Unused nametable fetches at the end of the line
+            bus.read(NAMETABLE_ADDRESS | (self.v & 0x0fff));
+        }
+
+        if dot == 256 {
+            self.increment_y();
+        } else if dot == 257 {
+            // v: ....A.. ...BCDEF <- t: ....A.. ...BCDEF
+            self.v = (self.v & !0x041f) | (self.t & 0x041f);
+        } else if self.scanline == self.get_pre_render_scanline() && (280..=304).contains(&dot) {
+            // v: GHIA.BC DEF..... <- t: GHIA.BC DEF.....
+            self.v = (self.v & !0x7be0) | (self.t & 0x7be0);
+        }
+    }
+
+    /// Gets the address of the pattern row of the next tile
+    fn get_background_pattern_address(&self) -> u16 {
+        let fine_y = (self.v >> 12) & 0x07;
+        self.get_background_pattern_table_address() + (self.next_tile_id as u16) * 16 + fine_y
+    }
+
+    fn shift_background(&mut self) {
+        if self.mask & FLAG_SHOW_BACKGROUND != 0 {
+            self.bg_pattern_shifter_lo <<= 1;
+            self.bg_pattern_shifter_hi <<= 1;
+            self.bg_palette_shifter_lo <<= 1;
+            self.bg_palette_shifter_hi <<= 1;
+        }
+    }
+
+    /// Loads the next tile in the low byte of the shift registers
+    fn load_background_shifters(&mut self) {
+        self.bg_pattern_shifter_lo = (self.bg_pattern_shifter_lo & 0xff00) | self.next_tile_pattern_lo as u16;
+        self.bg_pattern_shifter_hi = (self.bg_pattern_shifter_hi & 0xff00) | self.next_tile_pattern_hi as u16;
+
+        let palette_lo = if self.next_tile_palette & 0x01 != 0 { 0x00ff } else { 0x0000 };
+        let palette_hi = if self.next_tile_palette & 0x02 != 0 { 0x00ff } else { 0x0000 };
+        self.bg_palette_shifter_lo = (self.bg_palette_shifter_lo & 0xff00) | palette_lo;
+        self.bg_palette_shifter_hi = (self.bg_palette_shifter_hi & 0xff00) | palette_hi;
+    }
+
+    fn get_coarse_x(&self) -> u16 {
+        self.v & 0x001f
+    }
+
+    fn get_coarse_y(&self) -> u16 {
+        (self.v >> 5) & 0x001f
+    }
+
+    /// Moves v to the next tile, switching to the horizontally adjacent nametable at the end of a
+    /// row
+    fn increment_coarse_x(&mut self) {
+        if self.get_coarse_x() == 31 {
+            self.v &= !0x001f;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    /// Moves v to the next pixel row, switching to the vertically adjacent nametable after the 30th
+    /// row of tiles (rows 30 and 31 hold the attributes and wrap without switching)
+    fn increment_y(&mut self) {
+        if (self.v & 0x7000) != 0x7000 {
+            self.v += 0x1000;
+        } else {
+            self.v &= !0x7000;
+
+            let mut coarse_y = self.get_coarse_y();
+            if coarse_y == 29 {
+                coarse_y = 0;
+                self.v ^= 0x0800;
+            } else if coarse_y == 31 {
+                coarse_y = 0;
             } else {
-                current_status & (!FLAG_VBLANK)
-            };
-        memory.write(PPU_STATUS, updated_status)
+                coarse_y += 1;
+            }
+            self.v = (self.v & !0x03e0) | (coarse_y << 5);
+        }
     }
 
-    pub fn set_nmi_occurred(&self, memory: &mut Memory, status: bool) {
-        let current_status = memory.read(PPU_CTRL);
-        let updated_status =
-            if status {
-                current_status | FLAG_NMI_OCCURRED
+    /// Runs the sprite evaluation and fetches of the current dot
+    // https://www.nesdev.org/wiki/PPU_sprite_evaluation
+    fn render_sprites_dot<B: PpuBus>(&mut self, bus: &mut B) {
+        let dot = self.cycles;
+
+        if dot == 1 {
+            // The secondary OAM is cleared during the dots 1-64
+            self.secondary_oam = [0xff; 4 * SPRITES_PER_LINE];
+        } else if dot == 256 {
+            // The sprites are evaluated during the dots 65-256, there are none on the first line since
+            // the pre-render line does not evaluate any
+            if self.scanline == self.get_pre_render_scanline() {
+                self.next_sprite_count = 0;
+                self.next_sprite_zero = false;
             } else {
-                current_status & (!FLAG_NMI_OCCURRED)
-            };
-        memory.write(PPU_CTRL, updated_status)
+                self.evaluate_sprites();
+            }
+        } else if (257..=320).contains(&dot) {
+            self.oam_addr = 0;
+
+            // Each sprite takes 8 dots: two garbage nametable bytes, then the pattern low and high
+            // bytes, the unused slots fetch the tile $FF
+            let index = ((dot - 257) / 8) as usize;
+            match (dot - 257) % 8 {
+                0 => {
+                    self.sprite_attributes[index] = self.secondary_oam[index * 4 + 2];
+                    self.sprite_x[index] = self.secondary_oam[index * 4 + 3];
+                    bus.read(NAMETABLE_ADDRESS | (self.v & 0x0fff));
+                },
+                2 => {
+                    bus.read(NAMETABLE_ADDRESS | (self.v & 0x0fff));
+                },
+                4 => {
+                    let address = self.get_sprite_pattern_address(index);
+                    self.sprite_pattern_lo[index] = self.fetch_sprite_pattern(bus, index, address);
+                },
+                6 => {
+                    let address = self.get_sprite_pattern_address(index) + 8;
+                    self.sprite_pattern_hi[index] = self.fetch_sprite_pattern(bus, index, address);
+                },
+                _ => {}
+            }
+
+            if dot == 320 {
+                self.sprite_count = self.next_sprite_count;
+                self.sprite_zero = self.next_sprite_zero;
+            }
+        }
+    }
+
+    fn get_sprite_height(&self) -> u8 {
+        if self.ctrl & FLAG_SPRITE_HEIGHT != 0 { 16 } else { 8 }
+    }
+
+    /// Checks whether a sprite at the given Y position appears on the next scanline
+    fn is_sprite_in_range(&self, y: u8) -> bool {
+        let row = (self.scanline as i32) - (y as i32);
+        0 <= row && row < self.get_sprite_height() as i32
+    }
+
+    /// Copies the first 8 sprites of the next scanline in the secondary OAM, then looks for an
+    /// overflow with the hardware bug: after the 8th sprite, the byte index is incremented along with
+    /// the sprite index, so the tile, attributes and X bytes are compared as Y positions
+    fn evaluate_sprites(&mut self) {
+        let mut n = 0;
+        let mut count = 0;
+        self.next_sprite_zero = false;
+
+        while n < 64 && count < SPRITES_PER_LINE {
+            let y = self.oam[n * 4];
+            self.secondary_oam[count * 4] = y;
+
+            if self.is_sprite_in_range(y) {
+                self.secondary_oam[count * 4..count * 4 + 4].copy_from_slice(&self.oam[n * 4..n * 4 + 4]);
+                if n == 0 {
+                    self.next_sprite_zero = true;
+                }
+                count += 1;
+            }
+            n += 1;
+        }
+
+        let mut m = 0;
+        while n < 64 {
+            if self.is_sprite_in_range(self.oam[n * 4 + m]) {
+                self.status |= FLAG_SPRITE_OVERFLOW;
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 0x03;
+        }
+
+        self.next_sprite_count = count;
+    }
+
+    /// Gets the address of the pattern row of a sprite of the next scanline
+    fn get_sprite_pattern_address(&self, index: usize) -> u16 {
+        let y = self.secondary_oam[index * 4];
+        let tile = self.secondary_oam[index * 4 + 1];
+        let attributes = self.secondary_oam[index * 4 + 2];
+
+        let height = self.get_sprite_height() as u16;
+        let mut row = (self.scanline as u16).wrapping_sub(y as u16) & (height - 1);
+        if attributes & SPRITE_FLIP_VERTICAL != 0 {
+            row = height - 1 - row;
+        }
+
+        if height == 16 {
+            // 8x16 sprites take their pattern table from the bit 0 of the tile index, the bottom half
+            // is the next tile
+            let table = ((tile & 0x01) as u16) * 0x1000;
+            let tile = (tile & 0xfe) as u16 + row / 8;
+            table + tile * 16 + (row & 0x07)
+        } else {
+            self.get_sprite_pattern_table_address() + (tile as u16) * 16 + row
+        }
+    }
+
+    /// Fetches a pattern byte of a sprite, reversed when flipped horizontally and transparent for the
+    /// unused slots
+    fn fetch_sprite_pattern<B: PpuBus>(&self, bus: &mut B, index: usize, address: u16) -> u8 {
+        let mut pattern = bus.read(address);
+        if index >= self.next_sprite_count {
+            pattern = 0;
+        } else if self.sprite_attributes[index] & SPRITE_FLIP_HORIZONTAL != 0 {
+            pattern = pattern.reverse_bits();
+        }
+        pattern
+    }
+
+    /// Gets the pixel, the palette and the priority of the front-most opaque sprite at the given
+    /// position, the sprite index is returned to detect the sprite 0 hits
+    fn get_sprite_pixel(&self, x: usize) -> Option<(usize, u8, u8, bool)> {
+        for i in 0..self.sprite_count {
+            let offset = x as i32 - self.sprite_x[i] as i32;
+            if (0..8).contains(&offset) {
+                let bit = 0x80 >> offset;
+                let pixel = (((self.sprite_pattern_hi[i] & bit) != 0) as u8) << 1
+                    | ((self.sprite_pattern_lo[i] & bit) != 0) as u8;
+
+                if pixel != 0 {
+                    let attributes = self.sprite_attributes[i];
+                    return Some((i, pixel, attributes & SPRITE_PALETTE, attributes & SPRITE_BEHIND_BACKGROUND != 0));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Computes the pixel of the current dot and writes its color in the framebuffer
+    fn output_pixel(&mut self) {
+        let x = (self.cycles - 1) as usize;
+        let y = self.scanline as usize;
+
+        let mut bg_pixel = 0;
+        let mut bg_palette = 0;
+        if self.mask & FLAG_SHOW_BACKGROUND != 0 && (x >= 8 || self.mask & FLAG_SHOW_BACKGROUND_LEFT != 0) {
+            let mux = 0x8000 >> self.x;
+            bg_pixel = (((self.bg_pattern_shifter_hi & mux) != 0) as u8) << 1
+                | ((self.bg_pattern_shifter_lo & mux) != 0) as u8;
+            bg_palette = (((self.bg_palette_shifter_hi & mux) != 0) as u8) << 1
+                | ((self.bg_palette_shifter_lo & mux) != 0) as u8;
+        }
+
+        let mut sprite = None;
+        if self.mask & FLAG_SHOW_SPRITES != 0 && (x >= 8 || self.mask & FLAG_SHOW_SPRITES_LEFT != 0) {
+            sprite = self.get_sprite_pixel(x);
+        }
+
+        // The transparent pixels show the backdrop color
+        let palette_address = match sprite {
+            Some((index, sprite_pixel, sprite_palette, behind_background)) => {
+                // The sprite 0 hit is set when an opaque pixel of the sprite 0 overlaps an opaque
+                // background pixel, except on the last column
+                if index == 0 && self.sprite_zero && bg_pixel != 0 && x != 255 {
+                    self.status |= FLAG_SPRITE_0_HIT;
+                }
+
+                if bg_pixel != 0 && behind_background {
+                    PALETTE_ADDRESS + (bg_palette as u16) * 4 + bg_pixel as u16
+                } else {
+                    SPRITE_PALETTE_ADDRESS + (sprite_palette as u16) * 4 + sprite_pixel as u16
+                }
+            },
+            None if bg_pixel != 0 => PALETTE_ADDRESS + (bg_palette as u16) * 4 + bg_pixel as u16,
+            None => PALETTE_ADDRESS,
+        };
+
+        let mut emphasis = ((self.mask & (FLAG_EMPHASIZE_RED | FLAG_EMPHASIZE_GREEN | FLAG_EMPHASIZE_BLUE)) as u16) << 1;
+        if self.region != Region::NTSC {
+            // The red and green emphasis bits are swapped on the PAL PPUs
+            emphasis = (emphasis & 0b100_000000) | ((emphasis & 0b010_000000) >> 1) | ((emphasis & 0b001_000000) << 1);
+        }
+        self.framebuffer[y * SCREEN_WIDTH + x] = emphasis | self.read_palette(palette_address) as u16;
+    }
+    // endregion
+
+    // region Registers
+    /// Reads a PPU register ($2000-$2007), with its side effects
+    pub fn read_register<B: PpuBus>(&mut self, address: u16, bus: &mut B) -> u8 {
+        let val = match address {
+            PPU_STATUS => {
+                let val = (self.status & 0b11100000) | (self.io_latch & 0b00011111);
+
+                // Reading PPUSTATUS around the dot setting the VBLANK flag races with it: one dot
+                // before, the flag is read clear and is never set for this frame, and up to two dots
+                // after, the flag is read set but the NMI is lost in both cases
+                // https://www.nesdev.org/wiki/PPU_frame_timing#VBL_Flag_Timing
+                if self.scanline == self.region.get_vblank_scanline() {
+                    match self.cycles {
+                        0 => self.suppress_vblank = true,
+                        1 | 2 => self.cancel_nmi = true,
+                        _ => {}
+                    }
+                }
+
+                self.set_vblank(false);
+                self.w = false;
+                val
+            },
+            OAM_DATA => self.read_oam_data(),
+            PPU_DATA => {
+                let address = self.v & 0x3fff;
+                let val = if address >= PALETTE_ADDRESS {
+                    // Palette reads are not buffered, but the buffer is filled with the nametable
+                    // data mirrored underneath
+                    self.read_buffer = bus.read(address - 0x1000);
+                    self.read_palette(address) | (self.io_latch & 0b11000000)
+                } else {
+                    let val = self.read_buffer;
+                    self.read_buffer = bus.read(address);
+                    val
+                };
+
+                self.increment_vram_address(bus);
+                val
+            },
+            _ => self.io_latch
+        };
+
+        self.io_latch = val;
+        val
+    }
+
+    /// Gets the value of a PPU register ($2000-$2007) without side effects
+    pub fn peek_register(&self, address: u16) -> u8 {
+        match address {
+            PPU_STATUS => (self.status & 0b11100000) | (self.io_latch & 0b00011111),
+            OAM_DATA => self.read_oam_data(),
+            PPU_DATA => {
+                let address = self.v & 0x3fff;
+                if address >= PALETTE_ADDRESS {
+                    self.read_palette(address) | (self.io_latch & 0b11000000)
+                } else {
+                    self.read_buffer
+                }
+            },
+            _ => self.io_latch
+        }
+    }
+
+    /// Writes a PPU register ($2000-$2007)
+    pub fn write_register<B: PpuBus>(&mut self, address: u16, val: u8, bus: &mut B) {
+        self.io_latch = val;
+
+        match address {
+            PPU_CTRL => {
+                self.ctrl = val;
+                // t: ...GH.. ........ <- d: ......GH
+                self.t = (self.t & 0b1110011_11111111) | (((val & FLAG_NAMETABLE_SELECT) as u16) << 10);
+            },
+            PPU_MASK => self.mask = val,
+            OAM_ADDR => self.oam_addr = val,
+            OAM_DATA => self.write_oam_data(val),
+            PPU_SCROLL => {
+                if !self.w {
+                    // t: ....... ...ABCDE <- d: ABCDE...
+                    // x:              FGH <- d: .....FGH
+                    self.t = (self.t & 0b1111111_11100000) | ((val >> 3) as u16);
+                    self.x = val & 0b111;
+                } else {
+                    // t: FGH..AB CDE..... <- d: ABCDEFGH
+                    self.t = (self.t & 0b0001100_00011111)
+                        | (((val & 0b111) as u16) << 12)
+                        | (((val & 0b11111000) as u16) << 2);
+                }
+                self.w = !self.w;
+            },
+            PPU_ADDR => {
+                if !self.w {
+                    // t: .CDEFGH ........ <- d: ..CDEFGH, the bit 14 is cleared
+                    self.t = (self.t & 0b0000000_11111111) | (((val & 0b00111111) as u16) << 8);
+                } else {
+                    // t: ....... ABCDEFGH <- d: ABCDEFGH, then v = t
+                    self.t = (self.t & 0b1111111_00000000) | val as u16;
+                    self.v = self.t;
+                    if !self.is_rendering_enabled() {
+                        bus.set_address(self.v & 0x3fff);
+                    }
+                }
+                self.w = !self.w;
+            },
+            PPU_DATA => {
+                self.write_vram(self.v, val, bus);
+                self.increment_vram_address(bus);
+            },
+            _ => {}
+        }
+    }
+
+    /// Reads OAMDATA, the secondary OAM being cleared is read as $FF during the first dots of the
+    /// visible lines
+    fn read_oam_data(&self) -> u8 {
+        if self.is_rendering_enabled() && self.scanline <= 239 && 1 <= self.cycles && self.cycles <= 64 {
+            0xff
+        } else {
+            self.oam[self.oam_addr as usize]
+        }
+    }
+
+    /// Writes OAMDATA, during rendering the write is ignored but OAMADDR is still incremented (by one
+    /// sprite)
+    fn write_oam_data(&mut self, val: u8) {
+        if self.is_rendering_enabled() && (self.scanline <= 239 || self.scanline == self.get_pre_render_scanline()) {
+            self.oam_addr = self.oam_addr.wrapping_add(4);
+        } else {
+            self.write_oam(self.oam_addr, val);
+            self.oam_addr = self.oam_addr.wrapping_add(1);
+        }
+    }
+
+    /// Writes a byte of the OAM, the unimplemented bits of the attributes are cleared
+    fn write_oam(&mut self, address: u8, val: u8) {
+        let val = if address & 0x03 == 2 { val & SPRITE_ATTRIBUTES_MASK } else { val };
+        self.oam[address as usize] = val;
+    }
+
+    /// Increments the VRAM address after a PPUDATA access, by 1 or 32 depending on PPUCTRL, the
+    /// new address is driven on the bus when the rendering is disabled
+    fn increment_vram_address<B: PpuBus>(&mut self, bus: &mut B) {
+        let increment = if self.ctrl & FLAG_INCREMENT_MODE != 0 { 32 } else { 1 };
+        self.v = self.v.wrapping_add(increment) & 0x7fff;
+        if !self.is_rendering_enabled() {
+            bus.set_address(self.v & 0x3fff);
+        }
+    }
+    // endregion
+
+    /// Gets the level of the NMI output, asserted during the vertical blank when enabled in PPUCTRL
+    pub fn nmi_line(&self) -> bool {
+        self.get_vblank() && self.get_nmi_enable()
+    }
+
+    /// Returns whether a PPUSTATUS read cancelled the NMI of the vertical blank since the last call
+    pub fn take_nmi_cancel(&mut self) -> bool {
+        std::mem::take(&mut self.cancel_nmi)
+    }
+
+    pub fn get_nmi_enable(&self) -> bool {
+        self.ctrl & FLAG_NMI_ENABLE != 0
+    }
+
+    /// Gets the address of the pattern table used by the background
+    pub fn get_background_pattern_table_address(&self) -> u16 {
+        if self.ctrl & FLAG_BACKGROUND_TILE_SELECT != 0 { 0x1000 } else { 0x0000 }
+    }
+
+    /// Gets the address of the pattern table used by the 8x8 sprites
+    pub fn get_sprite_pattern_table_address(&self) -> u16 {
+        if self.ctrl & FLAG_SPRITE_TILE_SELECT != 0 { 0x1000 } else { 0x0000 }
+    }
+
+    /// Gets the VBLANK status
+    pub fn get_vblank(&self) -> bool {
+        self.status & FLAG_VBLANK != 0
+    }
+
+    /// Sets the VBLANK status
+    fn set_vblank(&mut self, status: bool) {
+        if status {
+            self.status |= FLAG_VBLANK;
+        } else {
+            self.status &= !FLAG_VBLANK;
+        }
+    }
+}
+
+/// Gets the index in the palette RAM of an address of $3F00-$3FFF, the backdrop entries of the
+/// sprite palettes ($3F10/$3F14/$3F18/$3F1C) are aliases of the background ones
+fn get_palette_index(address: u16) -> usize {
+    let index = address & 0x1f;
+    if index & 0x13 == 0x10 {
+        (index & 0x0f) as usize
+    } else {
+        index as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu_bus::Vram;
+
+    fn set_vram_address(ppu: &mut Ppu, vram: &mut Vram, address: u16) {
+        ppu.write_register(PPU_ADDR, (address >> 8) as u8, vram);
+        ppu.write_register(PPU_ADDR, address as u8, vram);
+    }
+
+    #[test]
+    fn ppudata_reads_are_buffered() {
+        let mut ppu = Ppu::new();
+        let mut vram = Vram::new();
+        ppu.write_vram(0x2000, 0x11, &mut vram);
+        ppu.write_vram(0x2001, 0x22, &mut vram);
+        set_vram_address(&mut ppu, &mut vram, 0x2000);
+
+        ppu.read_register(PPU_DATA, &mut vram);
+        assert_eq!(ppu.read_register(PPU_DATA, &mut vram), 0x11);
+        assert_eq!(ppu.read_register(PPU_DATA, &mut vram), 0x22);
+    }
+
+    #[test]
+    fn palette_reads_are_not_buffered() {
+        let mut ppu = Ppu::new();
+        let mut vram = Vram::new();
+        ppu.write_vram(0x3f00, 0x0f, &mut vram);
+        ppu.write_vram(0x2f00, 0x42, &mut vram);
+        set_vram_address(&mut ppu, &mut vram, 0x3f00);
+
+        assert_eq!(ppu.read_register(PPU_DATA, &mut vram), 0x0f);
+        assert_eq!(ppu.read_buffer, 0x42);
+    }
+
+    #[test]
+    fn ppudata_increment_mode() {
+        let mut ppu = Ppu::new();
+        let mut vram = Vram::new();
+        ppu.write_register(PPU_CTRL, FLAG_INCREMENT_MODE, &mut vram);
+        set_vram_address(&mut ppu, &mut vram, 0x2000);
+        ppu.write_register(PPU_DATA, 0x01, &mut vram);
+        ppu.write_register(PPU_DATA, 0x02, &mut vram);
+
+        assert_eq!(vram.read(0x2000), 0x01);
+        assert_eq!(vram.read(0x2020), 0x02);
+        assert_eq!(ppu.v, 0x2040);
+    }
+
+    #[test]
+    fn ppuscroll_sets_t_and_fine_x() {
+        let mut ppu = Ppu::new();
+        let mut vram = Vram::new();
+        ppu.write_register(PPU_CTRL, 0b00000001, &mut vram);
+        ppu.write_register(PPU_SCROLL, 0b01111101, &mut vram);
+        ppu.write_register(PPU_SCROLL, 0b01011110, &mut vram);
+
+        assert_eq!(ppu.t, 0b1100101_01101111);
+        assert_eq!(ppu.x, 0b101);
+    }
+
+    #[test]
+    fn ppustatus_read_clears_vblank_and_write_toggle() {
+        let mut ppu = Ppu::new();
+        let mut vram = Vram::new();
+        ppu.set_vblank(true);
+        ppu.write_register(PPU_ADDR, 0x21, &mut vram);
+
+        assert_eq!(ppu.read_register(PPU_STATUS, &mut vram) & FLAG_VBLANK, FLAG_VBLANK);
+        assert!(!ppu.get_vblank());
+        assert!(!ppu.w);
+    }
+
+    #[test]
+    fn ppustatus_read_before_vblank_suppresses_it() {
+        let mut ppu = Ppu::new();
+        let mut vram = Vram::new();
+        ppu.scanline = 241;
+        ppu.cycles = 0;
+
+        ppu.read_register(PPU_STATUS, &mut vram);
+        ppu.step(&mut vram);
+        assert!(!ppu.get_vblank());
+    }
+
+    fn set_sprite(ppu: &mut Ppu, index: usize, y: u8, tile: u8, attributes: u8, x: u8) {
+        ppu.oam[index * 4..index * 4 + 4].copy_from_slice(&[y, tile, attributes, x]);
+    }
+
+    #[test]
+    fn sprite_evaluation_keeps_eight_sprites_and_sets_overflow() {
+        let mut ppu = Ppu::new();
+        ppu.oam = [0xf0; 0x100];
+        for i in 0..9 {
+            set_sprite(&mut ppu, i, 10, i as u8, 0, 0);
+        }
+        ppu.scanline = 12;
+
+        ppu.evaluate_sprites();
+        assert_eq!(ppu.next_sprite_count, 8);
+        assert!(ppu.next_sprite_zero);
+        assert_eq!(ppu.secondary_oam[7 * 4 + 1], 7);
+        assert_ne!(ppu.status & FLAG_SPRITE_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn sprite_overflow_bug_reads_tile_index_as_y() {
+        let mut ppu = Ppu::new();
+        ppu.oam = [0xf0; 0x100];
+        for i in 1..9 {
+            set_sprite(&mut ppu, i, 10, 0, 0, 0);
+        }
+        // The 10th and 11th sprites are out of range, but the tile index of the 11th is compared as a
+        // Y position
+        set_sprite(&mut ppu, 10, 0xf0, 10, 0, 0);
+        ppu.scanline = 12;
+
+        ppu.evaluate_sprites();
+        assert_eq!(ppu.next_sprite_count, 8);
+        assert!(!ppu.next_sprite_zero);
+        assert_ne!(ppu.status & FLAG_SPRITE_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn tall_sprite_pattern_address_with_vertical_flip() {
+        let mut ppu = Ppu::new();
+        let mut vram = Vram::new();
+        ppu.write_register(PPU_CTRL, FLAG_SPRITE_HEIGHT, &mut vram);
+        ppu.secondary_oam[0..4].copy_from_slice(&[10, 0x23, SPRITE_FLIP_VERTICAL, 0]);
+
+        // First row of the sprite, flipped to the last row of the bottom tile
+        ppu.scanline = 10;
+        assert_eq!(ppu.get_sprite_pattern_address(0), 0x1000 + 0x23 * 16 + 7);
+        // Last row of the sprite, flipped to the first row of the top tile
+        ppu.scanline = 25;
+        assert_eq!(ppu.get_sprite_pattern_address(0), 0x1000 + 0x22 * 16);
+    }
+
+    #[test]
+    fn sprite_zero_hit_on_opaque_overlap() {
+        let mut ppu = Ppu::new();
+        let mut vram = Vram::new();
+        ppu.write_register(PPU_MASK, FLAG_SHOW_BACKGROUND | FLAG_SHOW_SPRITES, &mut vram);
+        ppu.sprite_count = 1;
+        ppu.sprite_zero = true;
+        ppu.sprite_pattern_lo[0] = 0x01;
+        ppu.sprite_x[0] = 10;
+        ppu.bg_pattern_shifter_lo = 0xffff;
+        ppu.scanline = 5;
+
+        // Transparent sprite pixel
+        ppu.cycles = 11;
+        ppu.output_pixel();
+        assert_eq!(ppu.status & FLAG_SPRITE_0_HIT, 0);
+
+        // Opaque sprite pixel
+        ppu.cycles = 18;
+        ppu.output_pixel();
+        assert_ne!(ppu.status & FLAG_SPRITE_0_HIT, 0);
+    }
+
+    #[test]
+    fn palette_backdrop_aliases() {
+        let mut ppu = Ppu::new();
+        let mut vram = Vram::new();
+        ppu.write_vram(0x3f10, 0x0d, &mut vram);
+        ppu.write_vram(0x3f1c, 0x2c, &mut vram);
+        ppu.write_vram(0x3f11, 0x15, &mut vram);
+
+        assert_eq!(ppu.read_palette(0x3f00), 0x0d);
+        assert_eq!(ppu.read_palette(0x3f0c), 0x2c);
+        assert_eq!(ppu.read_palette(0x3f01), 0x00);
+        // The palette RAM is mirrored up to $3FFF
+        assert_eq!(ppu.read_palette(0x3ff1), 0x15);
+    }
+
+    #[test]
+    fn pixels_carry_greyscale_and_emphasis() {
+        let mut ppu = Ppu::new();
+        let mut vram = Vram::new();
+        ppu.write_vram(0x3f00, 0x16, &mut vram);
+        ppu.write_register(PPU_MASK, FLAG_GREYSCALE | FLAG_EMPHASIZE_RED | FLAG_EMPHASIZE_BLUE, &mut vram);
+        ppu.scanline = 0;
+        ppu.cycles = 1;
+
+        ppu.output_pixel();
+        assert_eq!(ppu.get_framebuffer()[0], 0b101_010000);
+    }
+
+    fn run_frame(ppu: &mut Ppu, vram: &mut Vram) -> u32 {
+        let mut dots = 0;
+        let frame = ppu.get_frame();
+        while ppu.get_frame() == frame {
+            ppu.step(vram);
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn odd_frames_skip_a_dot_when_rendering() {
+        let mut ppu = Ppu::new();
+        let mut vram = Vram::new();
+        assert_eq!(run_frame(&mut ppu, &mut vram), 262 * 341);
+        assert_eq!(run_frame(&mut ppu, &mut vram), 262 * 341);
+
+        ppu.write_register(PPU_MASK, FLAG_SHOW_BACKGROUND, &mut vram);
+        assert_eq!(ppu.get_frame() % 2, 0);
+        assert_eq!(run_frame(&mut ppu, &mut vram), 262 * 341);
+        assert_eq!(run_frame(&mut ppu, &mut vram), 262 * 341 - 1);
+    }
+
+    #[test]
+    fn frame_complete_at_vblank() {
+        let mut ppu = Ppu::new();
+        let mut vram = Vram::new();
+        let mut dots = 0;
+        while !ppu.take_frame_complete() {
+            ppu.step(&mut vram);
+            dots += 1;
+        }
+
+        assert_eq!(dots, 241 * 341 + 1);
+        assert!(!ppu.take_frame_complete());
+    }
+
+    #[test]
+    fn pal_and_dendy_frame_timing() {
+        let mut ppu = Ppu::new();
+        let mut vram = Vram::new();
+        ppu.set_region(Region::PAL);
+        ppu.write_register(PPU_MASK, FLAG_SHOW_BACKGROUND, &mut vram);
+        assert_eq!(run_frame(&mut ppu, &mut vram), 312 * 341);
+        assert_eq!(run_frame(&mut ppu, &mut vram), 312 * 341);
+
+        let mut ppu = Ppu::new();
+        let mut vram = Vram::new();
+        ppu.set_region(Region::DENDY);
+        let mut dots = 0;
+        while !ppu.take_frame_complete() {
+            ppu.step(&mut vram);
+            dots += 1;
+        }
+        assert_eq!(dots, 291 * 341 + 1);
+    }
+
+    /// PPU bus recording the accesses of the rendering
+    struct RecordingBus {
+        vram: Vram,
+        dot: u32,
+        reads: Vec<(u32, u16)>,
+    }
+
+    impl PpuBus for RecordingBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.reads.push((self.dot, address));
+            self.vram.read(address)
+        }
+
+        fn write(&mut self, address: u16, val: u8) {
+            self.vram.write(address, val);
+        }
+
+        fn peek(&self, address: u16) -> u8 {
+            self.vram.peek(address)
+        }
+
+        fn tick(&mut self) {
+            self.dot += 1;
+        }
+    }
+
+    #[test]
+    fn scanline_fetches_and_a12_rising_edges() {
+        let mut ppu = Ppu::new();
+        let mut bus = RecordingBus { vram: Vram::new(), dot: 0, reads: Vec::new() };
+        ppu.write_register(PPU_CTRL, FLAG_SPRITE_TILE_SELECT, &mut bus);
+        ppu.write_register(PPU_MASK, FLAG_SHOW_BACKGROUND | FLAG_SHOW_SPRITES, &mut bus);
+
+        // Run the pre-render line and the first visible line
+        ppu.scanline = 261;
+        ppu.cycles = 0;
+        for _ in 0..341 {
+            ppu.step(&mut bus);
+        }
+        bus.reads.clear();
+        bus.dot = 1;
+        for _ in 0..340 {
+            ppu.step(&mut bus);
+        }
+
+        // 34 tiles of 4 bytes, 8 sprites of 4 bytes and 2 unused nametable bytes, the first two tiles
+        // were fetched at the end of the pre-render line
+        assert_eq!(bus.reads.len(), 170);
+        assert_eq!(bus.reads[0], (1, 0x2002));
+        assert_eq!(bus.reads.last(), Some(&(339, 0x2002)));
+
+        // The sprite patterns are read at $1000-$1FFF and the background ones at $0000-$0FFF: A12
+        // rises once for each sprite, the first time at the dot 261
+        let mut a12 = false;
+        let mut rising_edges = Vec::new();
+        for &(dot, address) in bus.reads.iter() {
+            let level = address & 0x1000 != 0;
+            if level && !a12 {
+                rising_edges.push(dot);
+            }
+            a12 = level;
+        }
+        assert_eq!(rising_edges.len(), 8);
+        assert_eq!(rising_edges[0], 261);
     }
 }
@@ -0,0 +1,166 @@
+use crate::rom_file::Mirroring;
+
+// https://www.nesdev.org/wiki/PPU_memory_map
+// https://www.nesdev.org/wiki/PPU_rendering
+// https://www.nesdev.org/wiki/Cartridge_connector
+
+/// Address bus of the PPU ($0000-$3EFF), wired to the cartridge. The palette RAM is internal to the
+/// PPU and is never seen on this bus.
+///
+/// The PPU issues each memory access of the rendering at the dot it happens (the first dot of the
+/// 2-dot access), then ticks the bus at the end of every dot, so that the cartridge can follow the
+/// address lines: A12 rising edges for the scanline counters, tile fetches for the CHR latches...
+pub trait PpuBus {
+    /// Reads the data at the given address, with the side effects of a PPU read
+    fn read(&mut self, address: u16) -> u8;
+
+    /// Writes the data at the given address (PPUDATA writes)
+    fn write(&mut self, address: u16, val: u8);
+
+    /// Reads the data at the given address without any side effect, for debugging purposes
+    fn peek(&self, address: u16) -> u8;
+
+    /// Called when the PPU drives an address without accessing the memory: the VRAM address set by
+    /// PPUADDR or incremented by PPUDATA while the rendering is disabled
+    fn set_address(&mut self, _address: u16) {}
+
+    /// Called at the start of each scanline (the pre-render line is the last one)
+    fn notify_scanline(&mut self, _scanline: u32) {}
+
+    /// Advances the cartridge by one PPU dot
+    fn tick(&mut self) {}
+}
+
+/// Nametables seen through the CIRAM, 2kB of VRAM in the console, plus 2kB on the cartridge for the
+/// four-screen mirroring
+pub struct Nametables {
+    ram: [u8; 0x1000],
+    mirroring: Mirroring,
+}
+
+impl Nametables {
+    pub fn new(mirroring: Mirroring) -> Nametables {
+        Nametables {
+            ram: [0; 0x1000],
+            mirroring,
+        }
+    }
+
+    /// Sets the nametable mirroring, it can be changed at runtime by some mappers
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        self.mirroring = mirroring;
+    }
+
+    pub fn get_mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    /// Reads a nametable byte ($2000-$3EFF)
+    pub fn read(&self, address: u16) -> u8 {
+        self.ram[self.get_index(address)]
+    }
+
+    /// Writes a nametable byte ($2000-$3EFF)
+    pub fn write(&mut self, address: u16, val: u8) {
+        let index = self.get_index(address);
+        self.ram[index] = val;
+    }
+
+    /// Gets the index in the nametables memory of an address of $2000-$3EFF, $3000-$3EFF mirrors
+    /// $2000-$2EFF
+    fn get_index(&self, address: u16) -> usize {
+        let nametable = (address >> 10) & 0x03;
+        let bank = match self.mirroring {
+            Mirroring::HORIZONTAL => nametable >> 1,
+            Mirroring::VERTICAL => nametable & 0x01,
+            Mirroring::SINGLE_SCREEN_A => 0,
+            Mirroring::SINGLE_SCREEN_B => 1,
+            Mirroring::FOUR_SCREEN => nametable,
+        };
+
+        (bank * 0x400 + (address & 0x03ff)) as usize
+    }
+}
+
+/// Flat video memory: 8kB of CHR RAM and the nametables with a fixed mirroring, without any mapper
+#[cfg(test)]
+pub struct Vram {
+    chr: Vec<u8>,
+    nametables: Nametables,
+}
+
+#[cfg(test)]
+impl Vram {
+    pub fn new() -> Vram {
+        Vram {
+            chr: vec![0; 0x2000],
+            nametables: Nametables::new(Mirroring::HORIZONTAL),
+        }
+    }
+}
+
+#[cfg(test)]
+impl PpuBus for Vram {
+    fn read(&mut self, address: u16) -> u8 {
+        self.peek(address)
+    }
+
+    fn write(&mut self, address: u16, val: u8) {
+        match address & 0x3fff {
+            0x0000..=0x1fff => self.chr[address as usize] = val,
+            _ => self.nametables.write(address, val)
+        }
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        match address & 0x3fff {
+            0x0000..=0x1fff => self.chr[address as usize],
+            _ => self.nametables.read(address)
+        }
+    }
+}
+
+/// Gets the 2-bit pixels of a tile of a pattern table, for debugging purposes
+pub fn get_chr_tile<B: PpuBus + ?Sized>(bus: &B, address: u16) -> [[u8; 8]; 8] {
+    let mut result = [[0; 8]; 8];
+
+    for (i, row) in result.iter_mut().enumerate() {
+        let lo = bus.peek(address + i as u16);
+        let hi = bus.peek(address + 8 + i as u16);
+        for (j, pixel) in row.iter_mut().enumerate() {
+            let lb = (lo >> (7 - j)) & 0x01;
+            let hb = (hi >> (7 - j)) & 0x01;
+            *pixel = (hb << 1) | lb;
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nametable_mirroring() {
+        let mut nametables = Nametables::new(Mirroring::HORIZONTAL);
+        nametables.write(0x2012, 0x01);
+        nametables.write(0x2812, 0x02);
+        assert_eq!(nametables.read(0x2412), 0x01);
+        assert_eq!(nametables.read(0x2c12), 0x02);
+
+        nametables.set_mirroring(Mirroring::VERTICAL);
+        assert_eq!(nametables.read(0x2812), 0x01);
+        assert_eq!(nametables.read(0x2412), 0x02);
+
+        nametables.set_mirroring(Mirroring::SINGLE_SCREEN_B);
+        assert_eq!(nametables.read(0x2012), 0x02);
+
+        nametables.set_mirroring(Mirroring::FOUR_SCREEN);
+        nametables.write(0x2c12, 0x03);
+        assert_eq!(nametables.read(0x2c12), 0x03);
+        assert_eq!(nametables.read(0x2412), 0x02);
+        // $3000-$3EFF mirrors the nametables
+        assert_eq!(nametables.read(0x3c12), 0x03);
+    }
+}
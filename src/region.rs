@@ -0,0 +1,66 @@
+// https://www.nesdev.org/wiki/Cycle_reference_chart
+// https://www.nesdev.org/wiki/NES_2.0#Byte_12_(CPU/PPU_Timing)
+
+/// TV system of the console, it selects the clock rates and the frame timing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    NTSC,
+    PAL,
+    /// Famiclone with PAL frame timing, but NTSC CPU:PPU clock ratio and APU timing
+    DENDY
+}
+
+impl Region {
+    /// Parses the name of a region (ntsc, pal or dendy)
+    pub fn parse(name: &str) -> Option<Region> {
+        match name.to_ascii_lowercase().as_str() {
+            "ntsc" => Some(Region::NTSC),
+            "pal" => Some(Region::PAL),
+            "dendy" => Some(Region::DENDY),
+            _ => None
+        }
+    }
+
+    /// Gets the number of scanlines of a frame, the last one is the pre-render line
+    pub fn get_scanlines(&self) -> u32 {
+        match self {
+            Region::NTSC => 262,
+            Region::PAL | Region::DENDY => 312,
+        }
+    }
+
+    /// Gets the scanline at which the vertical blank starts (and the NMI is raised)
+    pub fn get_vblank_scanline(&self) -> u32 {
+        match self {
+            Region::NTSC | Region::PAL => 241,
+            // 51 idle lines after the picture, so that the vertical blank is as long as on NTSC
+            Region::DENDY => 291,
+        }
+    }
+
+    /// Gets the number of PPU dots per CPU cycle, as a fraction (numerator, denominator)
+    pub fn get_ppu_dots_per_cpu_cycle(&self) -> (u32, u32) {
+        match self {
+            Region::NTSC | Region::DENDY => (3, 1),
+            Region::PAL => (16, 5),
+        }
+    }
+
+    /// Whether the pre-render line of the odd frames is one dot shorter when rendering
+    pub fn has_odd_frame_skip(&self) -> bool {
+        *self == Region::NTSC
+    }
+
+    /// Whether the APU frame counter runs with the PAL rates
+    pub fn has_pal_apu(&self) -> bool {
+        *self == Region::PAL
+    }
+
+    /// Gets the number of frames per second
+    pub fn get_frame_rate(&self) -> f64 {
+        match self {
+            Region::NTSC => 60.0988,
+            Region::PAL | Region::DENDY => 50.0070,
+        }
+    }
+}
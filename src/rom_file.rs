@@ -1,8 +1,9 @@
 use std::fs;
 use std::io;
+use crate::region::Region;
 
-const HEADER_SIZE: u16 = 16;
-const TRAINER_SIZE: u16 = 512;
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
 
 const FLAG_MIRRORING: u8 = 0b00000001;
 const FLAG_CARTRIDGE_BATTERY: u8 = 0b00000010;
@@ -10,9 +11,24 @@ const FLAG_TRAINER: u8 = 0b00000100;
 const FLAG_MIRRORING_CONTROL: u8 = 0b00001000;
 const FLAG_MAPPER: u8 = 0b11110000;
 
+const FLAG_NES2_IDENTIFIER: u8 = 0b00001100;
+const NES2_IDENTIFIER: u8 = 0b00001000;
+const FLAG_NES2_TIMING: u8 = 0b00000011;
+const FLAG_INES_TV_SYSTEM: u8 = 0b00000001;
+const FLAG_NES2_SUBMAPPER: u8 = 0b11110000;
+const FLAG_NES2_PRG_RAM_SHIFT: u8 = 0b00001111;
+const FLAG_NES2_PRG_NVRAM_SHIFT: u8 = 0b11110000;
+
+/// Nametable mirroring, selects which of the 2kB of CIRAM is seen by each of the 4 nametables
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
+    /// Lower 1kB bank of CIRAM for all the nametables
+    SINGLE_SCREEN_A,
+    /// Upper 1kB bank of CIRAM for all the nametables
+    SINGLE_SCREEN_B,
+    /// Additional 2kB of VRAM on the cartridge, each nametable has its own memory
     FOUR_SCREEN
 }
 
@@ -53,15 +69,15 @@ impl RomFile {
     }
 
     /// Gets the PRG ROM size in byte units
-    pub fn prg_size(&self) -> u16 {
-        (self.raw_prg_size() as u16) * (16 * 1024)
+    pub fn prg_size(&self) -> usize {
+        (self.raw_prg_size() as usize) * (16 * 1024)
     }
 
     /// Gets the PRG ROM data
     pub fn prg_data(&self) -> Vec<u8> {
         let prg_address = self.prg_data_address();
         let prg_size = self.prg_size();
-        return self.data[prg_address as usize .. (prg_address + prg_size) as usize].to_vec()
+        return self.data[prg_address .. prg_address + prg_size].to_vec()
     }
 
     /// Gets the CHR ROM size in 8kB units
@@ -70,15 +86,15 @@ impl RomFile {
     }
 
     /// Gets the CHR ROM size in 8kB units
-    pub fn chr_size(&self) -> u16 {
-        self.raw_chr_size() as u16 * (8 * 1024)
+    pub fn chr_size(&self) -> usize {
+        self.raw_chr_size() as usize * (8 * 1024)
     }
 
     /// Gets the CHR ROM data
     pub fn chr_data(&self) -> Vec<u8> {
         let chr_address = self.chr_data_address();
         let chr_size = self.chr_size();
-        return self.data[chr_address as usize .. (chr_address + chr_size) as usize].to_vec()
+        return self.data[chr_address .. chr_address + chr_size].to_vec()
     }
 
     /// Gets whether a trainer is present or no in the ROM file
@@ -86,9 +102,56 @@ impl RomFile {
         (self.data[6] & FLAG_TRAINER) != 0
     }
 
-    /// Gets the mapper type
+    /// Gets whether the cartridge contains battery-backed memory
+    pub fn has_battery(&self) -> bool {
+        (self.data[6] & FLAG_CARTRIDGE_BATTERY) != 0
+    }
+
+    /// Gets the PRG RAM size in byte units (volatile and battery-backed), iNES headers rarely give it
+    /// so 8kB are assumed when it is missing
+    pub fn prg_ram_size(&self) -> usize {
+        return if self.is_nes2() {
+            // Each size is given as a shift count: 64 << shift bytes, 0 for none
+            let shift_size = |shift: u8| if shift == 0 { 0 } else { 64 << shift };
+            shift_size(self.data[10] & FLAG_NES2_PRG_RAM_SHIFT) +
+                shift_size((self.data[10] & FLAG_NES2_PRG_NVRAM_SHIFT) >> 4)
+        } else {
+            (self.data[8].max(1) as usize) * (8 * 1024)
+        }
+    }
+
+    /// Gets whether the header declares PRG RAM, for the boards on which it is optional (like the
+    /// Family BASIC NROM board)
+    pub fn has_prg_ram(&self) -> bool {
+        return if self.is_nes2() {
+            self.prg_ram_size() > 0
+        } else {
+            self.has_battery() || self.data[8] != 0
+        }
+    }
+
+    /// Gets the mapper type, its low nibble is in the flags 6 and its high nibble in the flags 7
     pub fn get_mapper_type(&self) -> u8 {
-        (self.data[6] & FLAG_MAPPER) >> 4
+        let low = (self.data[6] & FLAG_MAPPER) >> 4;
+
+        // Old dumps have garbage in the bytes 7-15 (like "DiskDude!"), the flags 7 are then ignored
+        let high = if self.is_nes2() || self.data[12..16].iter().all(|&byte| byte == 0) {
+            self.data[7] & FLAG_MAPPER
+        } else {
+            0
+        };
+
+        high | low
+    }
+
+    /// Gets the submapper number of the NES 2.0 header, which tells the variants of a board apart (0
+    /// for the iNES headers)
+    pub fn get_submapper(&self) -> u8 {
+        if self.is_nes2() {
+            (self.data[8] & FLAG_NES2_SUBMAPPER) >> 4
+        } else {
+            0
+        }
     }
 
     /// Gets the mirroring type
@@ -104,7 +167,28 @@ impl RomFile {
         }
     }
 
-    pub fn prg_data_address(&self) -> u16 {
+    /// Gets whether the header is in the NES 2.0 format
+    pub fn is_nes2(&self) -> bool {
+        (self.data[7] & FLAG_NES2_IDENTIFIER) == NES2_IDENTIFIER
+    }
+
+    /// Gets the region from the timing bits of the NES 2.0 header, or from the TV system bit of the
+    /// iNES header (rarely set), multi-region games run as NTSC
+    pub fn get_region(&self) -> Region {
+        return if self.is_nes2() {
+            match self.data[12] & FLAG_NES2_TIMING {
+                1 => Region::PAL,
+                3 => Region::DENDY,
+                _ => Region::NTSC
+            }
+        } else if (self.data[9] & FLAG_INES_TV_SYSTEM) != 0 {
+            Region::PAL
+        } else {
+            Region::NTSC
+        }
+    }
+
+    pub fn prg_data_address(&self) -> usize {
         if self.has_trainer() {
             HEADER_SIZE + TRAINER_SIZE
         } else {
@@ -112,7 +196,7 @@ impl RomFile {
         }
     }
 
-    pub fn chr_data_address(&self) -> u16 {
+    pub fn chr_data_address(&self) -> usize {
         self.prg_data_address() + self.prg_size()
     }
 }
@@ -0,0 +1,2 @@
+mod nestest;
+mod processor_tests;
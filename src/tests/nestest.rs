@@ -0,0 +1,69 @@
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+use crate::mapper::new_mapper;
+use crate::memory::Memory;
+use crate::nes_debug::trace::trace;
+use crate::rom_file::RomFile;
+
+use std::fs;
+use std::path::PathBuf;
+
+// https://www.nesdev.org/wiki/Emulator_tests
+// The ROMs and their reference logs are expected in tests/nestest (see tests/nestest/README.md)
+
+/// In automation mode the tests start at $C000 instead of the reset vector, and don't need a PPU
+const AUTOMATION_START: u16 = 0xc000;
+
+/// Address where nestest writes the result code of the official and unofficial opcodes tests
+const RESULT_OFFICIAL: u16 = 0x0002;
+const RESULT_UNOFFICIAL: u16 = 0x0003;
+
+fn nestest_path(file_name: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "tests", "nestest", file_name].iter().collect()
+}
+
+/// Runs a ROM from $C000 and compares the trace of each instruction with the lines of its reference
+/// log, returns the memory at the end of the log
+fn run_reference_log(rom_name: &str, log_name: &str) -> Memory {
+    let rom_path = nestest_path(rom_name);
+    let log_path = nestest_path(log_name);
+    assert!(rom_path.exists() && log_path.exists(),
+            "{} or {} not found in tests/nestest, see tests/nestest/README.md", rom_name, log_name);
+
+    let rom_file = RomFile::new(rom_path.to_str().unwrap()).unwrap();
+    let mut memory = Memory::new(new_mapper(&rom_file).unwrap());
+
+    let mut cpu = Cpu::new(&mut memory);
+    cpu.set_pc(AUTOMATION_START);
+
+    let reference_log = fs::read_to_string(log_path).unwrap();
+    let mut previous_line = String::new();
+
+    for (i, expected) in reference_log.lines().enumerate() {
+        let ppu = memory.get_ppu();
+        let actual = trace(&cpu, &memory, ppu.get_scanline(), ppu.get_dot());
+        if actual != expected.trim_end() {
+            panic!("{} diverges at line {}\n  previous: {}\n  expected: {}\n  actual:   {}",
+                   log_name, i + 1, previous_line, expected.trim_end(), actual);
+        }
+
+        cpu.step(&mut memory);
+        previous_line = actual;
+    }
+
+    memory
+}
+
+#[test]
+fn cpu_trace_matches_reference_log() {
+    run_reference_log("cpu_trace.nes", "cpu_trace.log");
+}
+
+#[test]
+#[ignore = "nestest.nes and nestest.log are not redistributed, see tests/nestest/README.md"]
+fn nestest_matches_reference_log() {
+    let memory = run_reference_log("nestest.nes", "nestest.log");
+
+    assert_eq!(memory.peek(RESULT_OFFICIAL), 0, "official opcodes test failed");
+    assert_eq!(memory.peek(RESULT_UNOFFICIAL), 0, "unofficial opcodes test failed");
+}
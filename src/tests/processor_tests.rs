@@ -0,0 +1,158 @@
+use crate::cpu::{Cpu, Instruction};
+use crate::bus::{Bus, RamBus};
+use crate::opcodes::OPCODES;
+
+use serde::Deserialize;
+
+use std::fs;
+use std::path::PathBuf;
+
+// https://github.com/SingleStepTests/65x02/tree/main/nes6502
+// The JSON files (00.json to ff.json) are read from tests/processor_tests/nes6502, see
+// tests/processor_tests/README.md
+
+/// Number of failing cases detailed for each opcode
+const MAX_REPORTED_CASES: usize = 3;
+
+#[derive(Deserialize)]
+struct CpuState {
+    pc: u16,
+    s: u8,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+#[derive(Deserialize)]
+struct TestCase {
+    name: String,
+    initial: CpuState,
+    #[serde(rename = "final")]
+    expected: CpuState,
+    /// Bus activity of each cycle: address, value and "read" or "write"
+    cycles: Vec<(u16, u8, String)>,
+}
+
+fn test_file_path(opcode: u8) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "tests", "processor_tests", "nes6502", &format!("{:02x}.json", opcode)]
+        .iter()
+        .collect()
+}
+
+/// Formats a bus access like "read $1234: 56"
+fn format_access(access: Option<(u16, u8, &str)>) -> String {
+    match access {
+        Some((address, val, kind)) => format!("{} ${:04X}: {:02X}", kind, address, val),
+        None => "nothing".to_string(),
+    }
+}
+
+/// Runs a single test case on a flat RAM bus and returns the list of mismatches
+fn run_test_case(bus: &mut RamBus, test_case: &TestCase) -> Vec<String> {
+    let initial = &test_case.initial;
+    for &(address, val) in &initial.ram {
+        bus.write(address, val);
+    }
+
+    let mut cpu = Cpu::new(bus);
+    cpu.set_pc(initial.pc);
+    cpu.set_s(initial.s);
+    cpu.set_a(initial.a);
+    cpu.set_x(initial.x);
+    cpu.set_y(initial.y);
+    cpu.set_p(initial.p);
+
+    bus.clear_accesses();
+    let cycles = cpu.step(bus);
+
+    let expected = &test_case.expected;
+    let mut mismatches = Vec::new();
+
+    let registers = [
+        ("PC", cpu.get_pc(), expected.pc),
+        ("S", cpu.get_s() as u16, expected.s as u16),
+        ("A", cpu.get_a() as u16, expected.a as u16),
+        ("X", cpu.get_x() as u16, expected.x as u16),
+        ("Y", cpu.get_y() as u16, expected.y as u16),
+        ("P", cpu.get_p() as u16, expected.p as u16),
+    ];
+    for (name, actual, expected) in registers.iter() {
+        if actual != expected {
+            mismatches.push(format!("{}: expected {:02X}, got {:02X}", name, expected, actual));
+        }
+    }
+
+    // Peeked, the accesses of the instruction are compared below
+    for &(address, val) in &expected.ram {
+        let actual = bus.peek(address);
+        if actual != val {
+            mismatches.push(format!("${:04X}: expected {:02X}, got {:02X}", address, val, actual));
+        }
+    }
+
+    if cycles as usize != test_case.cycles.len() {
+        mismatches.push(format!("cycles: expected {}, got {}", test_case.cycles.len(), cycles));
+    }
+
+    // Each cycle does exactly one read or one write, the dummy ones included
+    let accesses = bus.get_accesses();
+    for i in 0..test_case.cycles.len().max(accesses.len()) {
+        let expected = test_case.cycles.get(i).map(|(address, val, kind)| (*address, *val, kind.as_str()));
+        let actual = accesses.get(i).copied();
+        if expected != actual {
+            mismatches.push(format!("cycle {}: expected {}, got {}",
+                                    i + 1, format_access(expected), format_access(actual)));
+            break;
+        }
+    }
+
+    // Clear the RAM for the next test case
+    for &(address, _) in initial.ram.iter().chain(expected.ram.iter()) {
+        bus.write(address, 0);
+    }
+    for (address, _, _) in &test_case.cycles {
+        bus.write(*address, 0);
+    }
+
+    mismatches
+}
+
+#[test]
+fn processor_tests() {
+    let mut bus = RamBus::new();
+    let mut report = Vec::new();
+    let mut tested_opcodes = 0;
+
+    for opcode in 0..=0xffu8 {
+        // A jammed CPU has no defined state to compare
+        if OPCODES[opcode as usize].instruction == Instruction::STP {
+            continue;
+        }
+
+        // Only a subset of the opcodes is stored in the repository
+        let path = test_file_path(opcode);
+        if !path.exists() {
+            continue;
+        }
+        tested_opcodes += 1;
+
+        let test_cases: Vec<TestCase> = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+
+        let failures: Vec<(&TestCase, Vec<String>)> = test_cases.iter()
+            .map(|test_case| (test_case, run_test_case(&mut bus, test_case)))
+            .filter(|(_, mismatches)| !mismatches.is_empty())
+            .collect();
+
+        if !failures.is_empty() {
+            report.push(format!("opcode 0x{:02x}: {}/{} cases failed", opcode, failures.len(), test_cases.len()));
+            for (test_case, mismatches) in failures.iter().take(MAX_REPORTED_CASES) {
+                report.push(format!("  {}: {}", test_case.name, mismatches.join(", ")));
+            }
+        }
+    }
+
+    assert!(tested_opcodes > 0, "No test file found, see tests/processor_tests/README.md");
+    assert!(report.is_empty(), "Processor tests failed:\n{}", report.join("\n"));
+}